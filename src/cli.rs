@@ -0,0 +1,383 @@
+use rssim::emulator::*;
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc::channel;
+
+const DEFAULT_CYCLES: usize = 1_000_000;
+
+// Exit codes of the run command
+const EXIT_OK: i32 = 0;
+const EXIT_TIMEOUT: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_FAULT: i32 = 3;
+
+const USAGE: &str = "\
+Usage: rssim run <program> [options]
+
+The program may be a listing (.LST), a source file (.asm) or an Intel HEX file.
+
+Options:
+  --cycles <n>          Stop after n instruction cycles (default 1000000)
+  --until-pc <address>  Stop when the pc reaches an address or label, e.g. 0x0010 or loop
+  --dump <sections>     Comma separated list of regs, ram, stack, eeprom or all (default regs)
+  --format <format>     Print the final state as text or json (default text)
+  --eeprom <file>       Load the eeprom from an image before running
+  --strict-stack        Stop on stack overflows and underflows
+
+Exit status: 0 when the program stopped as requested, 1 when --until-pc was not
+reached within the cycles, 2 on invalid arguments or programs and 3 on stack
+errors or invalid opcodes.";
+
+// Registers of the dump, addresses are register file addresses
+const DUMP_REGISTERS: [(&str, u8); 14] = [
+    ("TMR0", 0x01), ("PCL", 0x02), ("STATUS", 0x03), ("FSR", 0x04), ("PORTA", 0x05), ("PORTB", 0x06),
+    ("EEDATA", 0x08), ("EEADR", 0x09), ("PCLATH", 0x0a), ("INTCON", 0x0b), ("OPTION_REG", 0x81),
+    ("TRISA", 0x85), ("TRISB", 0x86), ("EECON1", 0x88),
+];
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum StopReason {
+    UntilPc,
+    Cycles,
+    StackError,
+    InvalidOpcode,
+}
+
+impl StopReason {
+    fn name(self) -> &'static str {
+        match self {
+            StopReason::UntilPc => "until-pc",
+            StopReason::Cycles => "cycles",
+            StopReason::StackError => "stack-error",
+            StopReason::InvalidOpcode => "invalid-opcode",
+        }
+    }
+}
+
+struct RunOptions {
+    program: String,
+    cycles: usize,
+    until_pc: Option<String>,
+    regs: bool,
+    ram: bool,
+    stack: bool,
+    eeprom: bool,
+    format: Format,
+    eeprom_file: Option<String>,
+    strict_stack: bool,
+}
+
+// Entry point of `rssim run`, returns the exit status of the process
+pub fn run(args: &[String]) -> i32 {
+    let options = match parse_options(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return EXIT_USAGE;
+        }
+    };
+
+    let mut cpu = match load_cpu(&options) {
+        Ok(cpu) => cpu,
+        Err(e) => {
+            eprintln!("{}", e);
+            return EXIT_USAGE;
+        }
+    };
+
+    let until_pc = match options.until_pc.as_deref().map(|target| resolve_address(&cpu, target)).transpose() {
+        Ok(until_pc) => until_pc,
+        Err(e) => {
+            eprintln!("{}", e);
+            return EXIT_USAGE;
+        }
+    };
+
+    let reason = execute(&mut cpu, options.cycles, until_pc);
+    print_state(&cpu, &options, reason);
+    exit_code(reason, until_pc.is_some())
+}
+
+// Running out of cycles is only a failure when the program should have reached a pc
+fn exit_code(reason: StopReason, until_pc: bool) -> i32 {
+    match reason {
+        StopReason::UntilPc => EXIT_OK,
+        StopReason::Cycles if !until_pc => EXIT_OK,
+        StopReason::Cycles => EXIT_TIMEOUT,
+        StopReason::StackError | StopReason::InvalidOpcode => EXIT_FAULT,
+    }
+}
+
+fn parse_options(args: &[String]) -> Result<RunOptions, String> {
+    let mut options = RunOptions {
+        program: String::new(),
+        cycles: DEFAULT_CYCLES,
+        until_pc: None,
+        regs: true,
+        ram: false,
+        stack: false,
+        eeprom: false,
+        format: Format::Text,
+        eeprom_file: None,
+        strict_stack: false,
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("Missing value for {}", arg));
+
+        match arg.as_str() {
+            "--cycles" => {
+                let cycles = value()?;
+                options.cycles = cycles.replace('_', "").parse().map_err(|_| format!("Invalid number of cycles {}", cycles))?;
+            }
+            "--until-pc" => options.until_pc = Some(value()?.clone()),
+            "--dump" => {
+                options.regs = false;
+                for section in value()?.split(',') {
+                    match section.trim() {
+                        "regs" => options.regs = true,
+                        "ram" => options.ram = true,
+                        "stack" => options.stack = true,
+                        "eeprom" => options.eeprom = true,
+                        "all" => {
+                            options.regs = true;
+                            options.ram = true;
+                            options.stack = true;
+                            options.eeprom = true;
+                        }
+                        section => return Err(format!("Unknown dump section {}", section)),
+                    }
+                }
+            }
+            "--format" => {
+                options.format = match value()?.as_str() {
+                    "text" => Format::Text,
+                    "json" => Format::Json,
+                    format => return Err(format!("Unknown format {}", format)),
+                }
+            }
+            "--eeprom" => options.eeprom_file = Some(value()?.clone()),
+            "--strict-stack" => options.strict_stack = true,
+            option if option.starts_with("--") => return Err(format!("Unknown option {}", option)),
+            program if options.program.is_empty() => options.program = program.to_string(),
+            argument => return Err(format!("Unexpected argument {}", argument)),
+        }
+    }
+
+    if options.program.is_empty() {
+        return Err(String::from("Missing program"));
+    }
+    Ok(options)
+}
+
+fn load_cpu(options: &RunOptions) -> Result<Cpu, String> {
+    let mut cpu = load_program(&options.program)?;
+    cpu.strict_stack = options.strict_stack;
+
+    if let Some(path) = &options.eeprom_file {
+        cpu.load_eeprom(Path::new(path))?;
+    }
+
+    Ok(cpu)
+}
+
+// Creates a cpu running the program of a listing, source or hex file
+pub fn load_program(path: &str) -> Result<Cpu, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let program = parse_program(path, &content).map_err(|e| format!("Failed to load {}: {}", path, e))?;
+
+    // Nothing drives the pins, so the weak pull-ups of portb can pull them high
+    let mut cpu = Cpu::new();
+    cpu.load(program);
+    Ok(cpu)
+}
+
+// Accepts a label of the program or a number like 0x0010, 10h or .16
+pub fn resolve_address(cpu: &Cpu, target: &str) -> Result<u16, String> {
+    let labels = &cpu.program_info().labels;
+    let label = labels.get(target).or_else(|| {
+        labels.iter().find(|(name, _)| name.eq_ignore_ascii_case(target)).map(|(_, address)| address)
+    });
+
+    label.copied()
+        .or_else(|| parse_value(target))
+        .ok_or_else(|| format!("Unknown address or label {}", target))
+}
+
+fn execute(cpu: &mut Cpu, max_cycles: usize, until_pc: Option<u16>) -> StopReason {
+    let (stack_errors_tx, stack_errors) = channel();
+    cpu.add_observer(Box::new(move |event: &SimEvent| {
+        if let SimEvent::StackError { .. } = event {
+            let _ = stack_errors_tx.send(());
+        }
+    }));
+
+    let mut reason = StopReason::Cycles;
+    let stopped = cpu.run_until(max_cycles, |cpu| {
+        if stack_errors.try_recv().is_ok() {
+            reason = StopReason::StackError;
+        } else if until_pc == Some(cpu.pc()) {
+            reason = StopReason::UntilPc;
+        }
+        reason != StopReason::Cycles
+    });
+
+    // No breakpoints or watchpoints are set, so only an opcode which can not be fetched breaks
+    match stopped {
+        Ok(_) => reason,
+        Err(_) => StopReason::InvalidOpcode,
+    }
+}
+
+fn print_state(cpu: &Cpu, options: &RunOptions, reason: StopReason) {
+    match options.format {
+        Format::Text => print_text(cpu, options, reason),
+        Format::Json => println!("{}", json_state(cpu, options, reason)),
+    }
+}
+
+fn print_text(cpu: &Cpu, options: &RunOptions, reason: StopReason) {
+    println!("stopped: {}", reason.name());
+    println!("cycles: {}", cpu.cycles);
+    match cpu.program_info().label_at(cpu.pc()) {
+        Some(label) => println!("pc: {:04x}h ({})", cpu.pc(), label),
+        None => println!("pc: {:04x}h", cpu.pc()),
+    }
+
+    if options.regs {
+        println!("W: {:02x}h", cpu.w());
+        for (name, address) in DUMP_REGISTERS.iter() {
+            println!("{}: {:02x}h", name, cpu.register(*address));
+        }
+    }
+    if options.ram {
+        for (row, bytes) in cpu.ram().chunks(8).enumerate() {
+            let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+            println!("ram {:02x}h: {}", GPR_START as usize + row * 8, bytes.join(" "));
+        }
+    }
+    if options.stack {
+        let entries: Vec<String> = cpu.stack().iter().map(|address| format!("{:04x}h", address)).collect();
+        println!("stack: {}", if entries.is_empty() { String::from("-") } else { entries.join(" ") });
+    }
+    if options.eeprom {
+        for (row, bytes) in cpu.eeprom().data.chunks(16).enumerate() {
+            let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+            println!("eeprom {:02x}h: {}", row * 16, bytes.join(" "));
+        }
+    }
+}
+
+// Written by hand, every value is a number or a fixed name which needs no escaping
+fn json_state(cpu: &Cpu, options: &RunOptions, reason: StopReason) -> String {
+    let mut fields = vec![
+        format!("\"stopped\":\"{}\"", reason.name()),
+        format!("\"cycles\":{}", cpu.cycles),
+        format!("\"pc\":{}", cpu.pc()),
+    ];
+
+    if options.regs {
+        let mut registers = vec![format!("\"W\":{}", cpu.w())];
+        registers.extend(DUMP_REGISTERS.iter().map(|(name, address)| format!("\"{}\":{}", name, cpu.register(*address))));
+        fields.push(format!("\"registers\":{{{}}}", registers.join(",")));
+    }
+    if options.ram {
+        fields.push(format!("\"ram\":{}", json_array(cpu.ram())));
+    }
+    if options.stack {
+        fields.push(format!("\"stack\":{}", json_array(&cpu.stack())));
+    }
+    if options.eeprom {
+        fields.push(format!("\"eeprom\":{}", json_array(&cpu.eeprom().data)));
+    }
+
+    format!("{{{}}}", fields.join(","))
+}
+
+fn json_array<T: ToString>(values: &[T]) -> String {
+    let values: Vec<String> = values.iter().map(T::to_string).collect();
+    format!("[{}]", values.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    fn cpu_for(source: &str) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.load(parse_program("test.asm", source).expect("Failed to assemble"));
+        cpu
+    }
+
+    #[test]
+    fn options_are_parsed() {
+        let options = parse_options(&args("loop.asm")).expect("Failed to parse options");
+        assert_eq!(options.program, "loop.asm");
+        assert_eq!(options.cycles, DEFAULT_CYCLES);
+        assert_eq!((options.regs, options.ram, options.stack, options.eeprom), (true, false, false, false));
+        assert_eq!(options.format, Format::Text);
+
+        let options = parse_options(&args("--cycles 10_000 loop.asm --until-pc ende --dump ram,stack --format json --strict-stack"))
+            .expect("Failed to parse options");
+        assert_eq!(options.cycles, 10_000);
+        assert_eq!(options.until_pc.as_deref(), Some("ende"));
+        assert_eq!((options.regs, options.ram, options.stack, options.eeprom), (false, true, true, false));
+        assert_eq!(options.format, Format::Json);
+        assert!(options.strict_stack);
+
+        let options = parse_options(&args("--dump all loop.asm")).expect("Failed to parse options");
+        assert_eq!((options.regs, options.ram, options.stack, options.eeprom), (true, true, true, true));
+
+        assert_eq!(parse_options(&args("")).err(), Some(String::from("Missing program")));
+        assert_eq!(parse_options(&args("loop.asm --cycles")).err(), Some(String::from("Missing value for --cycles")));
+        assert_eq!(parse_options(&args("loop.asm --cycles x")).err(), Some(String::from("Invalid number of cycles x")));
+        assert_eq!(parse_options(&args("loop.asm --dump flags")).err(), Some(String::from("Unknown dump section flags")));
+        assert_eq!(parse_options(&args("loop.asm --format xml")).err(), Some(String::from("Unknown format xml")));
+        assert_eq!(parse_options(&args("loop.asm --fast")).err(), Some(String::from("Unknown option --fast")));
+        assert_eq!(parse_options(&args("a.asm b.asm")).err(), Some(String::from("Unexpected argument b.asm")));
+    }
+
+    #[test]
+    fn stop_reasons_map_to_exit_codes() {
+        assert_eq!(exit_code(StopReason::UntilPc, true), EXIT_OK);
+        assert_eq!(exit_code(StopReason::Cycles, false), EXIT_OK);
+        assert_eq!(exit_code(StopReason::Cycles, true), EXIT_TIMEOUT);
+        assert_eq!(exit_code(StopReason::StackError, false), EXIT_FAULT);
+        assert_eq!(exit_code(StopReason::InvalidOpcode, false), EXIT_FAULT);
+
+        let source = "start   incf 0ch, f\n        goto start\n";
+        assert_eq!(execute(&mut cpu_for(source), 100, Some(1)), StopReason::UntilPc);
+        assert_eq!(execute(&mut cpu_for(source), 100, Some(2)), StopReason::Cycles);
+        assert_eq!(execute(&mut cpu_for("  nop\n"), 100, None), StopReason::InvalidOpcode);
+
+        let mut cpu = cpu_for("  return\n");
+        cpu.strict_stack = true;
+        assert_eq!(execute(&mut cpu, 100, None), StopReason::StackError);
+    }
+
+    #[test]
+    fn json_lists_the_dumped_sections() {
+        let mut cpu = cpu_for("  movlw 22h\n  call sub\nsub goto sub\n");
+        let reason = execute(&mut cpu, 5, None);
+
+        let mut options = parse_options(&args("sub.asm --format json")).expect("Failed to parse options");
+        let json = json_state(&cpu, &options, reason);
+        assert!(json.starts_with("{\"stopped\":\"cycles\",\"cycles\":5,\"pc\":2,\"registers\":{\"W\":34,\"TMR0\":"), "{}", json);
+        assert!(json.ends_with("\"EECON1\":0}}"), "{}", json);
+
+        options.regs = false;
+        options.stack = true;
+        assert_eq!(json_state(&cpu, &options, reason), "{\"stopped\":\"cycles\",\"cycles\":5,\"pc\":2,\"stack\":[2]}");
+    }
+}
@@ -0,0 +1,470 @@
+use crate::cli::{load_program, resolve_address};
+use rssim::emulator::*;
+use std::io::{self, BufRead, Write};
+
+// Continue, next and finish give up after this many instructions, so a program
+// that never reaches a breakpoint does not hang the console
+const RUN_LIMIT: usize = 10_000_000;
+// Instructions shown before and after the pc by list
+const LIST_CONTEXT: u16 = 5;
+// Registers shown by x without a count
+const DEFAULT_DUMP: usize = 16;
+
+const HELP: &str = "\
+step [n]             Execute n instructions (default 1)
+next                 Execute one instruction, a call runs until it returns
+finish               Run until the current subroutine returns
+continue             Run until a breakpoint or watchpoint is hit
+break [label|addr] [if <condition>]
+                     Set a breakpoint or list all breakpoints, e.g. break loop if W == 0x22 && Z
+ignore <label|addr> <n>
+                     Let a breakpoint pass n times before it stops
+delete <label|addr>  Remove a breakpoint
+watch [reg]          Stop when the program writes a register or list all watchpoints
+rwatch <reg>         Stop when the program reads a register
+awatch <reg>         Stop when the program reads or writes a register
+unwatch <reg>        Remove a watchpoint
+print <reg|symbol>   Show W, PC, a register or the register a symbol points to
+set <reg>=<value>    Change W, PC or a register
+x/<n> <addr>         Show n registers starting at an address
+stack                Show the return addresses, the most recent first
+list                 Show the source around the pc
+reset                Reset the cpu like the MCLR pin
+history              Show the commands entered so far
+quit                 Leave the debugger
+An empty line repeats the last command.";
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Register {
+    W,
+    Pc,
+    // Register file address (0x00-0xff)
+    File(u8),
+}
+
+// Why a run of the cpu ended
+enum Stop {
+    Done,
+    Limit,
+    Break(BreakReason),
+}
+
+// gdb like console on top of the cpu, breakpoints and watchpoints are the ones of the core
+pub struct Debugger {
+    cpu: Cpu,
+    history: Vec<String>,
+}
+
+// Entry point of `rssim debug`, returns the exit status of the process
+pub fn run(args: &[String]) -> i32 {
+    let path = match args {
+        [path] => path,
+        _ => {
+            eprintln!("Usage: rssim debug <program>");
+            return 2;
+        }
+    };
+
+    let mut debugger = match load_program(path) {
+        Ok(cpu) => Debugger::new(cpu),
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+    debugger.show_location();
+
+    let stdin = io::stdin();
+    loop {
+        print!("(rssim) ");
+        let _ = io::stdout().flush();
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+
+        match debugger.execute(line.trim()) {
+            Ok(true) => break,
+            Ok(false) => {}
+            Err(e) => println!("{}", e),
+        }
+    }
+
+    0
+}
+
+impl Debugger {
+    pub fn new(cpu: Cpu) -> Self {
+        Self { cpu, history: vec![] }
+    }
+
+    // Executes one command line, returns true when the debugger should quit
+    pub fn execute(&mut self, line: &str) -> Result<bool, String> {
+        let line = match (line, self.history.last()) {
+            ("", Some(last)) => last.clone(),
+            ("", None) => return Ok(false),
+            (line, _) => {
+                self.history.push(String::from(line));
+                String::from(line)
+            }
+        };
+
+        let (command, argument) = match line.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line.as_str(), ""),
+        };
+
+        match command {
+            "step" | "s" => {
+                let count = if argument.is_empty() { 1 } else { parse_count(argument)? };
+                let stop = self.run(count, |_| false);
+                self.report(stop);
+            }
+            "next" | "n" => self.next(),
+            "finish" => self.finish()?,
+            "continue" | "c" => {
+                let stop = self.run(RUN_LIMIT, |_| false);
+                self.report(stop);
+            }
+            "break" | "b" if argument.is_empty() => {
+                for breakpoint in self.cpu.breakpoints.iter() {
+                    let address = self.describe_address(breakpoint.address);
+                    let mut text = format!("Breakpoint at {}, hit {} times", address, breakpoint.hits);
+                    if let Some(condition) = &breakpoint.condition {
+                        text += &format!(", if {}", condition);
+                    }
+                    if breakpoint.ignore_count > 0 {
+                        text += &format!(", ignoring {} hits", breakpoint.ignore_count);
+                    }
+                    println!("{}", text);
+                }
+            }
+            "break" | "b" => {
+                let (target, condition) = match argument.split_once(" if ") {
+                    Some((target, condition)) => {
+                        (target.trim(), Some(Condition::parse(condition, self.cpu.program_info())?))
+                    }
+                    None => (argument, None),
+                };
+                let address = resolve_address(&self.cpu, target)?;
+                self.cpu.breakpoints.set(address, condition, 0);
+                println!("Breakpoint at {}", self.describe_address(address));
+            }
+            "ignore" => {
+                let (target, count) = argument.split_once(char::is_whitespace).ok_or("Usage: ignore <label|addr> <n>")?;
+                let address = resolve_address(&self.cpu, target)?;
+                let condition = self.cpu.breakpoints.get(address)
+                    .ok_or_else(|| format!("No breakpoint at {}", self.describe_address(address)))?
+                    .condition.clone();
+                self.cpu.breakpoints.set(address, condition, parse_count(count.trim())?);
+            }
+            "delete" | "d" => {
+                let address = resolve_address(&self.cpu, argument)?;
+                if !self.cpu.breakpoints.remove(address) {
+                    return Err(format!("No breakpoint at {}", self.describe_address(address)));
+                }
+            }
+            "watch" if argument.is_empty() => {
+                for watchpoint in self.cpu.data_bus.watchpoints.iter() {
+                    println!("Watchpoint on {} of {:02x}h, hit {} times", watchpoint.access, watchpoint.address, watchpoint.hits);
+                }
+            }
+            "watch" | "rwatch" | "awatch" => {
+                let address = register_address(argument, self.cpu.program_info())?;
+                let access = match command {
+                    "rwatch" => Access::Read,
+                    "awatch" => Access::ReadWrite,
+                    _ => Access::Write,
+                };
+                self.cpu.data_bus.watchpoints.set(address, access);
+                println!("Watchpoint on {} of {:02x}h", access, address);
+            }
+            "unwatch" => {
+                let address = register_address(argument, self.cpu.program_info())?;
+                if !self.cpu.data_bus.watchpoints.remove(address) {
+                    return Err(format!("No watchpoint on {:02x}h", address));
+                }
+            }
+            "print" | "p" => self.print(argument)?,
+            "set" => {
+                let (name, value) = argument.split_once('=').ok_or("Usage: set <reg>=<value>")?;
+                let register = self.resolve_register(name.trim())?;
+                let value = parse_value(value.trim()).ok_or_else(|| format!("Invalid value {}", value.trim()))?;
+                self.write(register, value);
+                self.print(name.trim())?;
+            }
+            "stack" => {
+                let stack = self.cpu.stack();
+                if stack.is_empty() {
+                    println!("The stack is empty");
+                }
+                for (depth, address) in stack.iter().rev().enumerate() {
+                    println!("#{} {}", depth, self.describe_address(*address));
+                }
+            }
+            "list" | "l" => self.list(),
+            "reset" => {
+                // Like the reset button of the gui
+                let kind = if self.cpu.sleeping { ResetKind::MclrSleep } else { ResetKind::Mclr };
+                self.cpu.reset(kind);
+                self.show_location();
+            }
+            "history" => {
+                for (index, command) in self.history.iter().enumerate() {
+                    println!("{:4}  {}", index + 1, command);
+                }
+            }
+            "help" | "h" => println!("{}", HELP),
+            "quit" | "q" => return Ok(true),
+            dump if dump == "x" || dump.starts_with("x/") => {
+                let count = match dump.strip_prefix("x/") {
+                    Some(count) => parse_count(count)?,
+                    None => DEFAULT_DUMP,
+                };
+                self.dump(argument, count)?;
+            }
+            _ => return Err(format!("Unknown command {}, try help", command)),
+        }
+
+        Ok(false)
+    }
+
+    // Steps until the condition holds, a breakpoint or watchpoint is hit or the limit is reached
+    fn run<F: FnMut(&Cpu) -> bool>(&mut self, max_steps: usize, mut done: F) -> Stop {
+        if max_steps == 0 {
+            return Stop::Limit;
+        }
+
+        // The cpu limits cycles, the debugger counts instructions
+        let mut steps = 0;
+        let mut finished = false;
+        let result = self.cpu.run_until(usize::MAX, |cpu| {
+            steps += 1;
+            finished = done(cpu);
+            finished || steps == max_steps
+        });
+
+        match result {
+            Err(reason) => Stop::Break(reason),
+            Ok(_) if finished => Stop::Done,
+            Ok(_) => Stop::Limit,
+        }
+    }
+
+    fn next(&mut self) {
+        let pc = self.cpu.pc();
+        let stop = match Instruction::from(self.cpu.rom_bus.read_opcode(pc)) {
+            Ok(Instruction::Call(_)) => {
+                let depth = self.cpu.stack().len();
+                self.run(RUN_LIMIT, |cpu| cpu.pc() == pc + 1 && cpu.stack().len() <= depth)
+            }
+            _ => self.run(1, |_| false),
+        };
+        self.report(stop);
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        let depth = self.cpu.stack().len();
+        if depth == 0 {
+            return Err(String::from("Not inside a subroutine, the stack is empty"));
+        }
+
+        let stop = self.run(RUN_LIMIT, |cpu| cpu.stack().len() < depth);
+        self.report(stop);
+        Ok(())
+    }
+
+    fn report(&self, stop: Stop) {
+        match stop {
+            Stop::Done => {}
+            Stop::Limit if self.cpu.sleeping => println!("The cpu is sleeping"),
+            Stop::Limit => {}
+            Stop::Break(BreakReason::Breakpoint { address, hits }) => {
+                println!("Breakpoint at {}, hit {} times", self.describe_address(address), hits)
+            }
+            Stop::Break(BreakReason::Watchpoint { address, access, value, hits }) => {
+                println!("Watchpoint: {} of {:02x}h with {:02x}h, hit {} times", access, address, value, hits)
+            }
+            Stop::Break(BreakReason::InvalidOpcode { address, error }) => {
+                println!("Invalid opcode at {}: {}", self.describe_address(address), error)
+            }
+        }
+        self.show_location();
+    }
+
+    fn show_location(&self) {
+        println!("=> {}", self.source_line(self.cpu.pc()));
+    }
+
+    fn list(&self) {
+        let (start, end) = self.cpu.rom_bus.get_rom_boundary();
+        let pc = self.cpu.pc();
+        let first = pc.saturating_sub(LIST_CONTEXT).max(start);
+        let last = pc.saturating_add(LIST_CONTEXT).min(end);
+
+        for address in first..=last {
+            let marker = if address == pc { "=>" } else { "  " };
+            println!("{} {}", marker, self.source_line(address));
+        }
+    }
+
+    // Line of the listing at an address, programs without a listing are disassembled
+    fn source_line(&self, address: u16) -> String {
+        let info = self.cpu.program_info();
+        let label = info.label_at(address).unwrap_or("");
+
+        let source = match info.lines.get(&address) {
+            Some(source) => match &source.comment {
+                Some(comment) => format!("{:05}  {} ;{}", source.line, source.text, comment),
+                None => format!("{:05}  {}", source.line, source.text),
+            },
+            None => Disassembler::new(info).opcode(self.cpu.rom_bus.read_opcode(address)),
+        };
+
+        format!("{:04x}h {:<10} {}", address, label, source)
+    }
+
+    fn describe_address(&self, address: u16) -> String {
+        match self.cpu.program_info().label_at(address) {
+            Some(label) => format!("{:04x}h ({})", address, label),
+            None => format!("{:04x}h", address),
+        }
+    }
+
+    fn print(&self, name: &str) -> Result<(), String> {
+        match self.resolve_register(name)? {
+            Register::W => println!("W = {:02x}h", self.cpu.w()),
+            Register::Pc => println!("PC = {}", self.describe_address(self.cpu.pc())),
+            Register::File(address) => {
+                println!("{} = [{:02x}h] {:02x}h", name, address, self.cpu.register(address))
+            }
+        }
+        Ok(())
+    }
+
+    fn dump(&self, start: &str, count: usize) -> Result<(), String> {
+        let start = match self.resolve_register(start)? {
+            Register::File(address) => address as usize,
+            _ => return Err(format!("{} is no register file address", start)),
+        };
+
+        let addresses: Vec<usize> = (start..start + count).take_while(|address| *address <= 0xff).collect();
+        for row in addresses.chunks(8) {
+            let bytes: Vec<String> = row.iter().map(|address| format!("{:02x}", self.cpu.register(*address as u8))).collect();
+            println!("{:02x}h: {}", row[0], bytes.join(" "));
+        }
+        Ok(())
+    }
+
+    // W, PC, special function registers, symbols of the program and plain addresses
+    fn resolve_register(&self, name: &str) -> Result<Register, String> {
+        if name.eq_ignore_ascii_case("w") {
+            return Ok(Register::W);
+        }
+        if name.eq_ignore_ascii_case("pc") {
+            return Ok(Register::Pc);
+        }
+
+        register_address(name, self.cpu.program_info()).map(Register::File)
+    }
+
+    fn write(&mut self, register: Register, value: u16) {
+        match register {
+            Register::W => self.cpu.set_w(value as u8),
+            Register::Pc => self.cpu.set_pc(value),
+            Register::File(address) => self.cpu.write_register(address, value as u8),
+        }
+    }
+}
+
+fn parse_count(text: &str) -> Result<usize, String> {
+    text.parse().map_err(|_| format!("Invalid count {}", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "\
+count   equ     0ch
+start   movlw   3
+        call    sub
+loop    incf    count, f
+        goto    loop
+sub     movwf   count
+        return
+";
+
+    fn debugger() -> Debugger {
+        let mut cpu = Cpu::new();
+        cpu.load(parse_program("test.asm", SOURCE).expect("Failed to assemble"));
+        Debugger::new(cpu)
+    }
+
+    #[test]
+    fn stepping_commands_move_the_pc() {
+        let mut debugger = debugger();
+
+        assert_eq!(debugger.execute("step"), Ok(false));
+        assert_eq!(debugger.cpu.pc(), 0x0001);
+        // next runs the whole subroutine
+        assert_eq!(debugger.execute("next"), Ok(false));
+        assert_eq!((debugger.cpu.pc(), debugger.cpu.register(0x0c)), (0x0002, 3));
+
+        debugger.execute("reset").unwrap();
+        assert_eq!(debugger.cpu.pc(), 0x0000);
+        debugger.execute("step 2").unwrap();
+        assert_eq!(debugger.cpu.pc(), 0x0004);
+        debugger.execute("finish").unwrap();
+        assert_eq!(debugger.cpu.pc(), 0x0002);
+        assert!(debugger.cpu.stack().is_empty());
+        assert_eq!(debugger.execute("finish"), Err(String::from("Not inside a subroutine, the stack is empty")));
+
+        // An empty line repeats the last command
+        debugger.execute("s").unwrap();
+        debugger.execute("").unwrap();
+        assert_eq!(debugger.cpu.pc(), 0x0002);
+        assert_eq!(debugger.history, vec!["step", "next", "reset", "step 2", "finish", "finish", "s"]);
+    }
+
+    #[test]
+    fn continue_stops_at_breakpoints_and_watchpoints() {
+        let mut debugger = debugger();
+
+        // loop is reached with count 3, 4 and 5
+        debugger.execute("break loop if count == 5").unwrap();
+        debugger.execute("continue").unwrap();
+        assert_eq!((debugger.cpu.pc(), debugger.cpu.register(0x0c)), (0x0002, 5));
+
+        debugger.execute("delete loop").unwrap();
+        assert_eq!(debugger.execute("delete loop"), Err(String::from("No breakpoint at 0002h (loop)")));
+        assert_eq!(debugger.execute("break nowhere"), Err(String::from("Unknown address or label nowhere")));
+
+        debugger.execute("watch count").unwrap();
+        debugger.execute("c").unwrap();
+        assert_eq!((debugger.cpu.pc(), debugger.cpu.register(0x0c)), (0x0003, 6));
+    }
+
+    #[test]
+    fn registers_are_shown_and_changed() {
+        let mut debugger = debugger();
+
+        debugger.execute("set W=0x42").unwrap();
+        assert_eq!(debugger.cpu.w(), 0x42);
+        debugger.execute("set count=7").unwrap();
+        assert_eq!(debugger.cpu.register(0x0c), 7);
+        debugger.execute("set PC=4").unwrap();
+        assert_eq!(debugger.cpu.pc(), 0x0004);
+        assert_eq!(debugger.execute("set count"), Err(String::from("Usage: set <reg>=<value>")));
+        assert_eq!(debugger.execute("set count=x"), Err(String::from("Invalid value x")));
+
+        assert_eq!(debugger.execute("x/4 count"), Ok(false));
+        assert_eq!(debugger.execute("x TRISB"), Ok(false));
+        assert_eq!(debugger.execute("x/4 W"), Err(String::from("W is no register file address")));
+        assert_eq!(debugger.execute("x/z count"), Err(String::from("Invalid count z")));
+
+        assert_eq!(debugger.execute("bogus"), Err(String::from("Unknown command bogus, try help")));
+        assert_eq!(debugger.execute("quit"), Ok(true));
+    }
+}
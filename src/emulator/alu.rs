@@ -0,0 +1,66 @@
+// Result of an arithmetic operation together with the status flags it affects
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct AluResult {
+    pub value: u8,
+    pub zero: bool,
+    pub carry: bool,
+    pub digit_carry: bool,
+}
+
+// Computes a + b, C and DC are the carries out of bit 7 and bit 3
+pub fn add(a: u8, b: u8) -> AluResult {
+    let (value, carry) = a.overflowing_add(b);
+
+    AluResult {
+        value,
+        zero: value == 0,
+        carry,
+        digit_carry: (a & 0xf) + (b & 0xf) > 0xf,
+    }
+}
+
+// Computes a - b as a + !b + 1, so C and DC are inverted borrows
+pub fn sub(a: u8, b: u8) -> AluResult {
+    let value = a.wrapping_sub(b);
+
+    AluResult {
+        value,
+        zero: value == 0,
+        carry: a >= b,
+        digit_carry: (a & 0xf) >= (b & 0xf),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(result: AluResult) -> (u8, bool, bool, bool) {
+        (result.value, result.carry, result.digit_carry, result.zero)
+    }
+
+    #[test]
+    fn add_sets_carry_digit_carry_and_zero() {
+        assert_eq!(flags(add(0x01, 0x02)), (0x03, false, false, false));
+        assert_eq!(flags(add(0x0f, 0x01)), (0x10, false, true, false));
+        assert_eq!(flags(add(0xf0, 0x20)), (0x10, true, false, false));
+        assert_eq!(flags(add(0x80, 0x80)), (0x00, true, false, true));
+        assert_eq!(flags(add(0xff, 0x01)), (0x00, true, true, true));
+        assert_eq!(flags(add(0x00, 0x00)), (0x00, false, false, true));
+    }
+
+    #[test]
+    fn sub_clears_carry_and_digit_carry_on_borrow() {
+        // No borrow at all, C and DC are set
+        assert_eq!(flags(sub(0x25, 0x13)), (0x12, true, true, false));
+        assert_eq!(flags(sub(0x05, 0x05)), (0x00, true, true, true));
+        assert_eq!(flags(sub(0x00, 0x00)), (0x00, true, true, true));
+        // Borrow out of the low nibble only
+        assert_eq!(flags(sub(0x10, 0x01)), (0x0f, true, false, false));
+        // Borrow out of bit 7 only
+        assert_eq!(flags(sub(0x0f, 0x10)), (0xff, false, true, false));
+        // Borrow out of both
+        assert_eq!(flags(sub(0x00, 0x01)), (0xff, false, false, false));
+        assert_eq!(flags(sub(0x7f, 0x80)), (0xff, false, true, false));
+    }
+}
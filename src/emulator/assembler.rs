@@ -0,0 +1,438 @@
+use super::parser::*;
+use super::program_info::*;
+use super::rom_bus::{CONFIG_WORD_ADDRESS, ID_LOCATIONS_ADDRESS};
+use std::collections::{BTreeMap, HashMap};
+
+// Registers, bits and configuration fuses of P16F84.INC, programs may redefine them
+const PREDEFINED_SYMBOLS: [(&str, u16); 54] = [
+    ("W", 0), ("F", 1),
+    ("INDF", 0x00), ("TMR0", 0x01), ("PCL", 0x02), ("STATUS", 0x03), ("FSR", 0x04), ("PORTA", 0x05),
+    ("PORTB", 0x06), ("EEDATA", 0x08), ("EEADR", 0x09), ("PCLATH", 0x0a), ("INTCON", 0x0b),
+    ("OPTION_REG", 0x81), ("TRISA", 0x85), ("TRISB", 0x86), ("EECON1", 0x88), ("EECON2", 0x89),
+    ("IRP", 7), ("RP1", 6), ("RP0", 5), ("NOT_TO", 4), ("NOT_PD", 3), ("Z", 2), ("DC", 1), ("C", 0),
+    ("GIE", 7), ("EEIE", 6), ("T0IE", 5), ("INTE", 4), ("RBIE", 3), ("T0IF", 2), ("INTF", 1), ("RBIF", 0),
+    ("NOT_RBPU", 7), ("INTEDG", 6), ("T0CS", 5), ("T0SE", 4), ("PSA", 3),
+    ("EEIF", 4), ("WRERR", 3), ("WREN", 2), ("WR", 1), ("RD", 0),
+    ("_CP_ON", 0x000f), ("_CP_OFF", 0x3fff), ("_PWRTE_ON", 0x3ff7), ("_PWRTE_OFF", 0x3fff), ("_WDT_ON", 0x3fff),
+    ("_WDT_OFF", 0x3ffb), ("_LP_OSC", 0x3ffc), ("_XT_OSC", 0x3ffd), ("_HS_OSC", 0x3ffe), ("_RC_OSC", 0x3fff),
+];
+
+// Statement of one source line, the operands are still unevaluated
+struct Statement<'a> {
+    line: usize,
+    source: &'a str,
+    address: u16,
+    // Radix of plain numbers like 255, hex unless changed with radix or list r=
+    radix: u32,
+    operation: &'a str,
+    operands: Vec<&'a str>,
+}
+
+// Statements of a source together with their code and the names they define
+struct Assembly<'a> {
+    statements: Vec<Statement<'a>>,
+    // Code words per statement, several for de
+    words: Vec<Vec<u16>>,
+    symbols: HashMap<String, i32>,
+    // Labels and equ symbols as written in the source, with their address or value
+    labels: Vec<(&'a str, u16)>,
+    equates: Vec<(&'a str, i32)>,
+}
+
+// Assembles 16F84 source into a listing in the MPASM layout, which parse_lst_file reads back.
+// Every source line becomes one listing line, so line numbers of both are the same.
+pub fn assemble(source: &str) -> Result<String, String> {
+    let assembly = assemble_statements(source)?;
+
+    let mut listing = String::new();
+    for (statement, words) in assembly.statements.iter().zip(&assembly.words) {
+        let prefix = match (statement.operation, words.as_slice()) {
+            ("equ", _) => format!("  {:08X}", assembly.symbols[&statement.operands[0].to_lowercase()]),
+            (_, []) => String::new(),
+            (_, words) => {
+                let words: Vec<String> = words.iter().map(|word| format!("{:04X}", word)).collect();
+                format!("{:04X} {}", word_address(statement), words.join(" "))
+            }
+        };
+
+        // de lists all its words in front of the line number, which keeps at least one space to them
+        listing += &format!("{:<19} {:05} {}\n", prefix, statement.line, statement.source);
+    }
+
+    Ok(listing)
+}
+
+// Assembles the source into a program, the names and lines are taken from the assembler
+// itself, so they do not depend on what a listing can express
+pub fn assemble_program(source: &str) -> Result<ParseResult, String> {
+    let assembly = assemble_statements(source)?;
+    let mut info = ProgramInfo::new();
+    // Word and source line per word address
+    let mut words: BTreeMap<u32, (u16, usize)> = BTreeMap::new();
+
+    for (statement, code) in assembly.statements.iter().zip(&assembly.words) {
+        let address = word_address(statement);
+
+        for (word_address, word) in (address..).zip(code) {
+            if let Some((_, line)) = words.insert(word_address, (*word, statement.line)) {
+                return Err(format!(
+                    "Line {}: Address {:04x}h is already used by line {}",
+                    statement.line, word_address, line
+                ));
+            }
+        }
+
+        if !code.is_empty() && address < ID_LOCATIONS_ADDRESS {
+            let (text, comment) = split_comment(statement.source);
+            info.lines.insert(address as u16, SourceLine { line: statement.line, text: String::from(text), comment });
+        }
+    }
+
+    for (name, address) in &assembly.labels {
+        info.add_label(name, *address);
+    }
+    for (name, value) in &assembly.equates {
+        info.add_symbol(name, *value as u16);
+    }
+
+    let mut result = program_image(&words)?;
+    result.info = info;
+    Ok(result)
+}
+
+// Word address the code of a statement is placed at
+fn word_address(statement: &Statement) -> u32 {
+    if statement.operation.eq_ignore_ascii_case("__config") {
+        CONFIG_WORD_ADDRESS
+    } else {
+        statement.address as u32
+    }
+}
+
+fn assemble_statements(source: &str) -> Result<Assembly<'_>, String> {
+    // Names are case insensitive like with MPASM /c-, so they are stored in lower case
+    let mut symbols: HashMap<String, i32> =
+        PREDEFINED_SYMBOLS.iter().map(|(name, value)| (name.to_lowercase(), *value as i32)).collect();
+    let mut labels: HashMap<String, usize> = HashMap::new();
+    let mut label_addresses = Vec::new();
+    let mut equates = Vec::new();
+    let mut statements = Vec::new();
+    let mut location: u16 = 0;
+    let mut radix = 16;
+
+    // First pass: assign addresses to labels and evaluate equ
+    for (line, text) in (1..).zip(source.lines()) {
+        let mut code = split_comment(text).0;
+        let tokens: Vec<&str> = code.split_whitespace().collect();
+
+        if tokens.len() >= 2 && ["equ", "set"].contains(&tokens[1].to_lowercase().as_str()) {
+            let value = evaluate(&tokens[2..].concat(), &symbols, location, radix).map_err(|e| format!("Line {}: {}", line, e))?;
+            symbols.insert(tokens[0].to_lowercase(), value);
+            equates.push((tokens[0], value));
+            statements.push(Statement { line, source: text, address: location, radix, operation: "equ", operands: vec![tokens[0]] });
+            continue;
+        }
+
+        // Labels start in column 1 or end with a colon, an indented name is only taken as
+        // label when an instruction follows, so a misspelled mnemonic is reported as such
+        let is_label = |token: &str| {
+            label_name(token).is_some()
+                && (token.ends_with(':')
+                    || !text.starts_with(char::is_whitespace)
+                    || tokens.get(1).is_none_or(|next| is_operation(next)))
+        };
+        if let Some(token) = tokens.first().copied().filter(|token| is_label(token)) {
+            let label = label_name(token).unwrap_or(token);
+            if let Some(previous) = labels.insert(label.to_lowercase(), line) {
+                return Err(format!("Line {}: Label {} is already defined in line {}", line, label, previous));
+            }
+            symbols.insert(label.to_lowercase(), location as i32);
+            label_addresses.push((label, location));
+            code = code[token.len()..].trim_start();
+        }
+
+        let operation = code.split_whitespace().next().unwrap_or("");
+        // Operands are separated by commas, spaces inside an operand are allowed
+        let operands: Vec<&str> = match code[operation.len()..].trim() {
+            "" => vec![],
+            operands => operands.split(',').map(str::trim).collect(),
+        };
+        if operation.is_empty() {
+            statements.push(Statement { line, source: text, address: location, radix, operation, operands });
+            continue;
+        }
+
+        let statement = Statement { line, source: text, address: location, radix, operation, operands };
+        match operation.to_lowercase().as_str() {
+            "org" => {
+                let address = evaluate(statement.operands.first().copied().unwrap_or(""), &symbols, location, radix)
+                    .map_err(|e| format!("Line {}: {}", line, e))?;
+                location = address as u16;
+            }
+            "de" => location += statement.operands.len() as u16,
+            "radix" => radix = radix_of(statement.operands.first().copied().unwrap_or(""), line)?,
+            "list" => {
+                for option in &statement.operands {
+                    if let Some(value) = option.strip_prefix("r=").or_else(|| option.strip_prefix("R=")) {
+                        radix = radix_of(value, line)?;
+                    }
+                }
+            }
+            "end" => {
+                statements.push(statement);
+                break;
+            }
+            mnemonic if MNEMONICS.contains(&mnemonic) => location += 1,
+            "__config" | "device" | "processor" | "errorlevel" => {}
+            "include" | "#include" if statement.operands.iter().any(|o| o.to_lowercase().contains("p16f84")) => {}
+            _ => return Err(format!("Line {}: Unknown instruction or directive {}", line, operation)),
+        }
+        statements.push(statement);
+    }
+
+    // Second pass: encode the instructions with all labels known
+    let words = statements
+        .iter()
+        .map(|statement| encode(statement, &symbols).map_err(|e| format!("Line {}: {}", statement.line, e)))
+        .collect::<Result<Vec<Vec<u16>>, String>>()?;
+
+    Ok(Assembly { statements, words, symbols, labels: label_addresses, equates })
+}
+
+fn encode(statement: &Statement, symbols: &HashMap<String, i32>) -> Result<Vec<u16>, String> {
+    let operation = statement.operation.to_lowercase();
+    let operand = |index: usize| -> Result<i32, String> {
+        let text = statement.operands.get(index).ok_or_else(|| format!("Missing operand for {}", statement.operation))?;
+        evaluate(text, symbols, statement.address, statement.radix)
+    };
+    // File registers of bank 1 are addressed with their lower seven bits
+    let file = || operand(0).map(|f| (f & 0x7f) as u16);
+    // Without a destination the result goes to the file register
+    let destination = || match statement.operands.get(1).map(|d| d.to_lowercase()).as_deref() {
+        None | Some("f") => Ok(1 << 7),
+        Some("w") => Ok(0),
+        Some(_) => operand(1).map(|d| ((d & 1) as u16) << 7),
+    };
+    let bit = || operand(1).and_then(|b| match b {
+        0..=7 => Ok((b as u16) << 7),
+        _ => Err(format!("Bit number {} is out of range", b)),
+    });
+    let literal = || operand(0).map(|k| (k & 0xff) as u16);
+    let address = || operand(0).map(|k| (k & 0x7ff) as u16);
+
+    let word = match operation.as_str() {
+        "addwf" => 0x0700 | destination()? | file()?,
+        "andwf" => 0x0500 | destination()? | file()?,
+        "clrf" => 0x0180 | file()?,
+        "clrw" => 0x0100,
+        "comf" => 0x0900 | destination()? | file()?,
+        "decf" => 0x0300 | destination()? | file()?,
+        "decfsz" => 0x0b00 | destination()? | file()?,
+        "incf" => 0x0a00 | destination()? | file()?,
+        "incfsz" => 0x0f00 | destination()? | file()?,
+        "iorwf" => 0x0400 | destination()? | file()?,
+        "movf" => 0x0800 | destination()? | file()?,
+        "movwf" => 0x0080 | file()?,
+        "nop" => 0x0000,
+        "rlf" => 0x0d00 | destination()? | file()?,
+        "rrf" => 0x0c00 | destination()? | file()?,
+        "subwf" => 0x0200 | destination()? | file()?,
+        "swapf" => 0x0e00 | destination()? | file()?,
+        "xorwf" => 0x0600 | destination()? | file()?,
+        "bcf" => 0x1000 | bit()? | file()?,
+        "bsf" => 0x1400 | bit()? | file()?,
+        "btfsc" => 0x1800 | bit()? | file()?,
+        "btfss" => 0x1c00 | bit()? | file()?,
+        "addlw" => 0x3e00 | literal()?,
+        "andlw" => 0x3900 | literal()?,
+        "call" => 0x2000 | address()?,
+        "clrwdt" => 0x0064,
+        "goto" => 0x2800 | address()?,
+        "iorlw" => 0x3800 | literal()?,
+        "movlw" => 0x3000 | literal()?,
+        "retfie" => 0x0009,
+        "retlw" => 0x3400 | literal()?,
+        "return" => 0x0008,
+        "sleep" => 0x0063,
+        "sublw" => 0x3c00 | literal()?,
+        "xorlw" => 0x3a00 | literal()?,
+        // Every eeprom byte takes a whole word
+        "de" => {
+            return (0..statement.operands.len()).map(|i| operand(i).map(|value| (value & 0xff) as u16)).collect();
+        }
+        "__config" => (operand(0)? & 0x3fff) as u16,
+        _ => return Ok(vec![]),
+    };
+
+    Ok(vec![word])
+}
+
+fn radix_of(name: &str, line: usize) -> Result<u32, String> {
+    match name.to_lowercase().as_str() {
+        "hex" => Ok(16),
+        "dec" => Ok(10),
+        "oct" => Ok(8),
+        _ => Err(format!("Line {}: Unknown radix {}", line, name)),
+    }
+}
+
+fn is_operation(token: &str) -> bool {
+    let lower = token.to_lowercase();
+    MNEMONICS.contains(&lower.as_str()) || DIRECTIVES.contains(&lower.as_str()) || lower == "#include"
+}
+
+// Evaluates operands like wert1, 0ch, 'A', $-1 or _CP_OFF & _WDT_OFF strictly from left to right
+fn evaluate(expression: &str, symbols: &HashMap<String, i32>, location: u16, radix: u32) -> Result<i32, String> {
+    let expression = expression.trim();
+    if expression.is_empty() {
+        return Err(String::from("Missing operand"));
+    }
+
+    let mut result = 0;
+    let mut operator = '+';
+    let mut rest = expression;
+
+    loop {
+        let end = rest.find(['+', '-', '&', '|']).unwrap_or(rest.len());
+        let (term, next) = rest.split_at(end);
+        let term = term.trim();
+
+        let value = if term.is_empty() && rest.len() == expression.len() {
+            // A leading sign like in -1
+            0
+        } else if term == "$" {
+            location as i32
+        } else if let Some(character) = term.strip_prefix('\'').and_then(|t| t.strip_suffix('\'')).filter(|t| t.len() == 1) {
+            // Character constant like 'I'
+            character.as_bytes()[0] as i32
+        } else if let Some(value) = symbols.get(&term.to_lowercase()) {
+            *value
+        } else if radix != 16 && term.chars().all(|c| c.is_ascii_digit()) {
+            i32::from_str_radix(term, radix).map_err(|_| format!("Invalid number {}", term))?
+        } else if term.starts_with(|c: char| c.is_ascii_digit() || c == '.') || term.contains('\'') {
+            parse_value(term).ok_or_else(|| format!("Invalid number {}", term))? as i32
+        } else {
+            return Err(format!("Undefined symbol {}", term));
+        };
+
+        result = match operator {
+            '+' => result + value,
+            '-' => result - value,
+            '&' => result & value,
+            _ => result | value,
+        };
+
+        match next.chars().next() {
+            Some(next_operator) => {
+                operator = next_operator;
+                rest = &next[1..];
+            }
+            None => return Ok(result),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn assembler_reproduces_the_listings() {
+        for number in 1..=15 {
+            let listing = fs::read_to_string(format!("programs/TPicSim{}.LST", number)).expect("Failed to read listing");
+            let expected = parse_lst_file(&listing).expect("Failed to parse listing");
+
+            // The source text starts two columns behind the line number
+            let mut source: Vec<&str> = listing.lines().map(|line| line.get(27..).unwrap_or("")).collect();
+            if number == 12 {
+                // This program was assembled with decimal as default radix (xorlw 255)
+                let empty = source.iter().position(|line| line.trim().is_empty()).expect("No empty line");
+                source[empty] = "  radix dec";
+            }
+            let assembled = assemble_program(&source.join("\n")).unwrap_or_else(|e| panic!("TPicSim{}: {}", number, e));
+
+            assert_eq!(assembled.program, expected.program, "TPicSim{}", number);
+            for address in expected.info.lines.keys() {
+                assert_eq!(assembled.info.line_of(*address), expected.info.line_of(*address), "TPicSim{}", number);
+            }
+        }
+    }
+    #[test]
+    fn assembler_directives_and_errors() {
+        let source = "\
+        __config _CP_OFF & _WDT_OFF
+count   equ     h'0C'
+        org     0
+start:  movlw   b'1010'
+        addlw   .10
+        bsf     STATUS, RP0
+        decfsz  count,w
+        goto    $-1
+        org     0x2100
+        de      1, 2
+        end
+";
+        let listing = assemble(source).expect("Failed to assemble");
+        assert!(listing.starts_with("2007 3FFB           00001"), "{}", listing);
+
+        let program = parse_lst_file(&listing).expect("Failed to parse assembled listing");
+        assert_eq!(program.program, vec![0x30, 0x0a, 0x3e, 0x0a, 0x16, 0x83, 0x0b, 0x0c, 0x28, 0x03]);
+        assert_eq!(program.config_word, Some(0x3ffb));
+        assert_eq!(program.eeprom.map(|eeprom| eeprom[..2].to_vec()), Some(vec![1, 2]));
+        assert_eq!(program.info.labels.get("start"), Some(&0));
+
+        assert_eq!(assemble("  movlw missing").err(), Some(String::from("Line 1: Undefined symbol missing")));
+        assert_eq!(assemble("  mvlw 1").err(), Some(String::from("Line 1: Unknown instruction or directive mvlw")));
+        assert_eq!(assemble("  bsf 3,8").err(), Some(String::from("Line 1: Bit number 8 is out of range")));
+    }
+
+    #[test]
+    fn assembler_lists_long_de_lines() {
+        let source = "\
+        movlw   1
+        org     0x2100
+        de      1, 2, 3, 4, 5
+";
+        let listing = assemble(source).expect("Failed to assemble");
+        assert!(listing.contains("2100 0001 0002 0003 0004 0005 00003"), "{}", listing);
+
+        let program = parse_lst_file(&listing).expect("Failed to parse assembled listing");
+        assert_eq!(program.program, vec![0x30, 0x01]);
+        assert_eq!(program.eeprom.map(|eeprom| eeprom[..6].to_vec()), Some(vec![1, 2, 3, 4, 5, 0xff]));
+        assert_eq!(program.info.line_of(0x0000), Some(1));
+    }
+
+    #[test]
+    fn assembler_program_keeps_names_and_addresses() {
+        let source = "\
+        radix   dec
+val     equ     10
+x       equ     0x0c+1
+RESET   equ     0
+        org     RESET
+        goto    start
+        org     100
+start   movlw   val     ; 10 in the decimal radix
+        movwf   x
+";
+        let program = assemble_program(source).expect("Failed to assemble");
+        let info = &program.info;
+
+        assert_eq!(info.symbols.get("val"), Some(&10));
+        assert_eq!(info.symbols.get("x"), Some(&0x0d));
+        assert_eq!(info.symbols.get("RESET"), Some(&0));
+        assert_eq!(info.labels.get("start"), Some(&100));
+        assert_eq!(info.line_of(0), Some(6));
+        assert_eq!(info.lines[&100].text, "start   movlw   val");
+        assert_eq!(info.lines[&100].comment.as_deref(), Some("10 in the decimal radix"));
+        assert_eq!(program.program[..2], [0x28, 0x64]);
+        assert_eq!(program.program[200..], [0x30, 0x0a, 0x00, 0x8d]);
+
+        let overlapping = "  nop\n  org 0\n  clrw\n";
+        assert_eq!(
+            assemble_program(overlapping).err(),
+            Some(String::from("Line 3: Address 0000h is already used by line 1"))
+        );
+    }
+}
@@ -52,6 +52,13 @@ pub const WREN: usize = 2;
 pub const WR: usize = 1;
 pub const RD: usize = 0;
 
+// Bit constants for configuration word
+pub const CP: usize = 4;
+pub const PWRTE: usize = 3;
+pub const WDTE: usize = 2;
+pub const FOSC1: usize = 1;
+pub const FOSC0: usize = 0;
+
 pub fn set_bit_enabled(value: &mut u8, bit: usize, enabled: bool) {
     if enabled {
         set_bit(value, bit);
@@ -0,0 +1,377 @@
+use super::bits::*;
+use super::cpu::Cpu;
+use super::data_bus::canonical_address;
+use super::disassembler::{flag_bit, sfr_address};
+use super::parser::parse_value;
+use super::program_info::ProgramInfo;
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn includes(self, access: Access) -> bool {
+        self == Access::ReadWrite || self == access
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::ReadWrite => write!(f, "access"),
+        }
+    }
+}
+
+// Why the cpu stopped, reported to the frontend with SimEvent::BreakpointHit
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum BreakReason {
+    // The pc arrived at a breakpoint whose condition holds
+    Breakpoint { address: u16, hits: usize },
+    // The program accessed a watched register, value is the value read or written
+    Watchpoint { address: u8, access: Access, value: u8, hits: usize },
+    // The instruction at the pc could not be fetched, the cpu can not continue
+    InvalidOpcode { address: u16, error: String },
+}
+
+impl fmt::Display for BreakReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BreakReason::Breakpoint { address, hits } => write!(f, "breakpoint {:04x}h hit {}", address, hits),
+            BreakReason::Watchpoint { address, access, value, hits } => {
+                write!(f, "watchpoint {:02x}h {} {:02x}h hit {}", address, access, value, hits)
+            }
+            BreakReason::InvalidOpcode { address, error } => write!(f, "invalid opcode {:04x}h {}", address, error),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Operand {
+    W,
+    Pc,
+    // Register file address (0x00-0xff)
+    Register(u8),
+    Flag(u8, usize),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+// Longer operators first, so <= is not taken for <
+const OPERATORS: [(&str, Operator); 6] = [
+    ("==", Operator::Equal),
+    ("!=", Operator::NotEqual),
+    ("<=", Operator::LessEqual),
+    (">=", Operator::GreaterEqual),
+    ("<", Operator::Less),
+    (">", Operator::Greater),
+];
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Comparison {
+    operand: Operand,
+    operator: Operator,
+    value: u16,
+}
+
+// Condition of a breakpoint like W == 0x22 && Z, && binds stronger than ||.
+// A register or flag on its own is true when it is not 0, !Z when it is 0.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Condition {
+    text: String,
+    alternatives: Vec<Vec<Comparison>>,
+}
+
+impl Condition {
+    pub fn parse(text: &str, info: &ProgramInfo) -> Result<Self, String> {
+        let alternatives = text
+            .split("||")
+            .map(|alternative| alternative.split("&&").map(|term| parse_comparison(term.trim(), info)).collect())
+            .collect::<Result<Vec<Vec<Comparison>>, String>>()?;
+
+        Ok(Self { text: String::from(text.trim()), alternatives })
+    }
+
+    pub fn evaluate(&self, cpu: &Cpu) -> bool {
+        self.alternatives.iter().any(|terms| {
+            terms.iter().all(|comparison| {
+                let value = match comparison.operand {
+                    Operand::W => cpu.w() as u16,
+                    Operand::Pc => cpu.pc(),
+                    Operand::Register(address) => cpu.register(address) as u16,
+                    Operand::Flag(address, bit) => get_bit(cpu.register(address), bit) as u16,
+                };
+
+                match comparison.operator {
+                    Operator::Equal => value == comparison.value,
+                    Operator::NotEqual => value != comparison.value,
+                    Operator::Less => value < comparison.value,
+                    Operator::LessEqual => value <= comparison.value,
+                    Operator::Greater => value > comparison.value,
+                    Operator::GreaterEqual => value >= comparison.value,
+                }
+            })
+        })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+fn parse_comparison(term: &str, info: &ProgramInfo) -> Result<Comparison, String> {
+    if let Some(operand) = term.strip_prefix('!').filter(|operand| !operand.starts_with('=')) {
+        return Ok(Comparison { operand: parse_operand(operand.trim(), info)?, operator: Operator::Equal, value: 0 });
+    }
+
+    let split = OPERATORS.iter().find_map(|(symbol, operator)| {
+        term.split_once(symbol).map(|(operand, value)| (operand.trim(), *operator, value.trim()))
+    });
+
+    match split {
+        Some((operand, operator, value)) => {
+            let value = parse_value(value)
+                .or_else(|| find_symbol(info, value))
+                .ok_or_else(|| format!("Invalid value {}", value))?;
+            Ok(Comparison { operand: parse_operand(operand, info)?, operator, value })
+        }
+        None => Ok(Comparison { operand: parse_operand(term, info)?, operator: Operator::NotEqual, value: 0 }),
+    }
+}
+
+fn parse_operand(name: &str, info: &ProgramInfo) -> Result<Operand, String> {
+    if name.eq_ignore_ascii_case("w") {
+        Ok(Operand::W)
+    } else if name.eq_ignore_ascii_case("pc") {
+        Ok(Operand::Pc)
+    } else if let Some((address, bit)) = flag_bit(name) {
+        Ok(Operand::Flag(address, bit))
+    } else {
+        register_address(name, info).map(Operand::Register)
+    }
+}
+
+// Register file address of a special function register, a symbol of the program or a number
+pub fn register_address(name: &str, info: &ProgramInfo) -> Result<u8, String> {
+    if let Some(address) = sfr_address(name) {
+        return Ok(address);
+    }
+
+    match find_symbol(info, name).or_else(|| parse_value(name)) {
+        Some(address) if address <= 0xff => Ok(address as u8),
+        Some(address) => Err(format!("{} ({:x}h) is no register file address", name, address)),
+        None => Err(format!("Unknown register or symbol {}", name)),
+    }
+}
+
+fn find_symbol(info: &ProgramInfo, name: &str) -> Option<u16> {
+    info.symbols.get(name).copied().or_else(|| {
+        info.symbols.iter().find(|(symbol, _)| symbol.eq_ignore_ascii_case(name)).map(|(_, value)| *value)
+    })
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Breakpoint {
+    pub address: u16,
+    pub condition: Option<Condition>,
+    // Number of hits to let pass before the cpu stops
+    pub ignore_count: usize,
+    // How often the pc arrived here with the condition holding
+    pub hits: usize,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Watchpoint {
+    pub address: u8,
+    pub access: Access,
+    pub hits: usize,
+}
+
+// Address breakpoints, checked by the cpu after every instruction
+pub struct Breakpoints {
+    breakpoints: BTreeMap<u16, Breakpoint>,
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Self { breakpoints: BTreeMap::new() }
+    }
+
+    // Replaces an existing breakpoint at the same address
+    pub fn set(&mut self, address: u16, condition: Option<Condition>, ignore_count: usize) {
+        self.breakpoints.insert(address, Breakpoint { address, condition, ignore_count, hits: 0 });
+    }
+
+    pub fn remove(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn get(&self, address: u16) -> Option<&Breakpoint> {
+        self.breakpoints.get(&address)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.breakpoints.values()
+    }
+
+    // Counts a hit at the address and tells whether the cpu has to stop
+    pub(crate) fn hit(&mut self, address: u16) -> Option<BreakReason> {
+        let breakpoint = self.breakpoints.get_mut(&address)?;
+        breakpoint.hits += 1;
+
+        if breakpoint.hits > breakpoint.ignore_count {
+            Some(BreakReason::Breakpoint { address, hits: breakpoint.hits })
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for Breakpoints {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Data watchpoints, the data bus reports every read and write of the program to them.
+// Mirrored registers like STATUS or the general purpose registers are watched in both banks.
+pub struct Watchpoints {
+    watchpoints: BTreeMap<u8, Watchpoint>,
+    // First hit of the current instruction, taken by the cpu when it finished
+    hit: Option<BreakReason>,
+    // Accesses only hit while the cpu executes an instruction
+    armed: bool,
+}
+
+impl Watchpoints {
+    pub fn new() -> Self {
+        Self { watchpoints: BTreeMap::new(), hit: None, armed: false }
+    }
+
+    pub fn arm(&mut self, armed: bool) {
+        self.armed = armed;
+    }
+
+    pub fn set(&mut self, address: u8, access: Access) {
+        let address = canonical_address(address);
+        self.watchpoints.insert(address, Watchpoint { address, access, hits: 0 });
+    }
+
+    pub fn remove(&mut self, address: u8) -> bool {
+        self.watchpoints.remove(&canonical_address(address)).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Watchpoint> {
+        self.watchpoints.values()
+    }
+
+    pub fn on_access(&mut self, address: u8, access: Access, value: u8) {
+        if !self.armed || self.watchpoints.is_empty() {
+            return;
+        }
+
+        if let Some(watchpoint) = self.watchpoints.get_mut(&canonical_address(address)) {
+            if watchpoint.access.includes(access) {
+                watchpoint.hits += 1;
+                let reason = BreakReason::Watchpoint { address: watchpoint.address, access, value, hits: watchpoint.hits };
+                self.hit.get_or_insert(reason);
+            }
+        }
+    }
+
+    pub fn take_hit(&mut self) -> Option<BreakReason> {
+        self.hit.take()
+    }
+}
+
+impl Default for Watchpoints {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{assemble_program, to_protocol, ResetKind, SimEvent};
+
+    #[test]
+    fn breakpoints_and_watchpoints_stop_the_cpu() {
+        let source = "\
+count   equ     0ch
+        org     0
+start   movlw   3
+        movwf   count
+loop    decfsz  count, f
+        goto    loop
+        bsf     STATUS, RP0
+        movf    8ch, w          ; count through its bank 1 mirror
+ende    goto    ende
+";
+        let mut cpu = Cpu::new();
+        cpu.load(assemble_program(source).expect("Failed to assemble"));
+        let run_to_break = |cpu: &mut Cpu| (0..100).find_map(|_| cpu.step());
+
+        // The pc arrives at loop with count 3, 2 and 1
+        let condition = Condition::parse("count == 1 && !Z", cpu.program_info()).expect("Failed to parse condition");
+        cpu.breakpoints.set(0x0002, Some(condition), 0);
+        assert_eq!(run_to_break(&mut cpu), Some(BreakReason::Breakpoint { address: 0x0002, hits: 1 }));
+        assert_eq!(cpu.register(0x0c), 1);
+
+        cpu.reset(ResetKind::PowerOn);
+        cpu.breakpoints.set(0x0002, None, 1);
+        assert_eq!(run_to_break(&mut cpu), Some(BreakReason::Breakpoint { address: 0x0002, hits: 2 }));
+        assert_eq!(cpu.register(0x0c), 2);
+
+        // Watchpoints on a mirror watch the register in both banks
+        cpu.reset(ResetKind::PowerOn);
+        cpu.breakpoints.clear();
+        cpu.data_bus.watchpoints.set(0x8c, Access::Write);
+        let expected = BreakReason::Watchpoint { address: 0x0c, access: Access::Write, value: 3, hits: 1 };
+        assert_eq!(run_to_break(&mut cpu), Some(expected));
+        assert_eq!(cpu.pc(), 0x0002);
+
+        cpu.data_bus.watchpoints.set(0x0c, Access::Read);
+        let expected = BreakReason::Watchpoint { address: 0x0c, access: Access::Read, value: 3, hits: 1 };
+        assert_eq!(run_to_break(&mut cpu), Some(expected));
+        assert_eq!(cpu.pc(), 0x0003);
+
+        // Changes from outside the program do not hit
+        cpu.data_bus.watchpoints.set(0x0c, Access::ReadWrite);
+        cpu.write_register(0x0c, 0x10);
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.data_bus.watchpoints.iter().map(|w| w.hits).collect::<Vec<_>>(), vec![0]);
+
+        let hit = SimEvent::BreakpointHit(BreakReason::Breakpoint { address: 0x0010, hits: 2 });
+        assert_eq!(to_protocol(&hit), vec![String::from("BREAK breakpoint 0010h hit 2")]);
+        assert_eq!(
+            Condition::parse("W == 0x22 && missing", cpu.program_info()).err(),
+            Some(String::from("Unknown register or symbol missing"))
+        );
+    }
+}
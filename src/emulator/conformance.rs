@@ -0,0 +1,183 @@
+// Checks every instruction against the instruction set summary of the datasheet
+
+use super::bits::*;
+use super::cpu::Cpu;
+use super::parser::ParseResult;
+
+// General purpose register used as file operand by all cases
+const F: u8 = 0x20;
+// Return address on the stack before every case
+const RETURN_ADDRESS: u16 = 0x0123;
+
+// Mnemonic, opcode, (W, f, STATUS) before, (W, f, STATUS) after, cycles, pc after
+type Case = (&'static str, u16, [u8; 3], [u8; 3], usize, u16);
+
+// STATUS starts with TO and PD set (0x18), the low bits are C (1), DC (2) and Z (4)
+const CASES: &[Case] = &[
+    ("ADDWF f,w", 0x0720, [0x0f, 0x01, 0x18], [0x10, 0x01, 0x1a], 1, 0x0001),
+    ("ADDWF f,f", 0x07a0, [0x80, 0x80, 0x18], [0x80, 0x00, 0x1d], 1, 0x0001),
+    ("ANDWF f,w", 0x0520, [0xf0, 0x0f, 0x1b], [0x00, 0x0f, 0x1f], 1, 0x0001),
+    ("ANDWF f,f", 0x05a0, [0x3c, 0x0f, 0x1c], [0x3c, 0x0c, 0x18], 1, 0x0001),
+    ("CLRF f", 0x01a0, [0x12, 0x55, 0x18], [0x12, 0x00, 0x1c], 1, 0x0001),
+    ("CLRW", 0x0100, [0x55, 0x12, 0x1b], [0x00, 0x12, 0x1f], 1, 0x0001),
+    ("COMF f,w", 0x0920, [0x12, 0xff, 0x18], [0x00, 0xff, 0x1c], 1, 0x0001),
+    ("COMF f,f", 0x09a0, [0x12, 0x0f, 0x1c], [0x12, 0xf0, 0x18], 1, 0x0001),
+    ("DECF f,f", 0x03a0, [0x00, 0x01, 0x18], [0x00, 0x00, 0x1c], 1, 0x0001),
+    ("DECF f,w", 0x0320, [0x00, 0x00, 0x1c], [0xff, 0x00, 0x18], 1, 0x0001),
+    ("DECFSZ f,f", 0x0ba0, [0x00, 0x02, 0x18], [0x00, 0x01, 0x18], 1, 0x0001),
+    ("DECFSZ f,f", 0x0ba0, [0x00, 0x01, 0x18], [0x00, 0x00, 0x18], 2, 0x0002),
+    ("DECFSZ f,w", 0x0b20, [0x33, 0x01, 0x18], [0x00, 0x01, 0x18], 2, 0x0002),
+    ("INCF f,f", 0x0aa0, [0x00, 0xff, 0x19], [0x00, 0x00, 0x1d], 1, 0x0001),
+    ("INCF f,w", 0x0a20, [0x00, 0x41, 0x1c], [0x42, 0x41, 0x18], 1, 0x0001),
+    ("INCFSZ f,f", 0x0fa0, [0x00, 0xff, 0x18], [0x00, 0x00, 0x18], 2, 0x0002),
+    ("INCFSZ f,f", 0x0fa0, [0x00, 0x10, 0x18], [0x00, 0x11, 0x18], 1, 0x0001),
+    ("IORWF f,w", 0x0420, [0x00, 0x00, 0x18], [0x00, 0x00, 0x1c], 1, 0x0001),
+    ("IORWF f,f", 0x04a0, [0x0f, 0xf0, 0x1c], [0x0f, 0xff, 0x18], 1, 0x0001),
+    ("MOVF f,w", 0x0820, [0x12, 0x00, 0x18], [0x00, 0x00, 0x1c], 1, 0x0001),
+    ("MOVF f,f", 0x08a0, [0x12, 0x34, 0x1c], [0x12, 0x34, 0x18], 1, 0x0001),
+    ("MOVWF f", 0x00a0, [0x56, 0x00, 0x1c], [0x56, 0x56, 0x1c], 1, 0x0001),
+    ("NOP", 0x0000, [0x12, 0x34, 0x1f], [0x12, 0x34, 0x1f], 1, 0x0001),
+    ("RLF f,f", 0x0da0, [0x00, 0x80, 0x18], [0x00, 0x00, 0x19], 1, 0x0001),
+    ("RLF f,w", 0x0d20, [0x00, 0x41, 0x19], [0x83, 0x41, 0x18], 1, 0x0001),
+    ("RRF f,f", 0x0ca0, [0x00, 0x01, 0x18], [0x00, 0x00, 0x19], 1, 0x0001),
+    ("RRF f,w", 0x0c20, [0x00, 0x02, 0x1d], [0x81, 0x02, 0x1c], 1, 0x0001),
+    ("SUBWF f,w", 0x0220, [0x01, 0x02, 0x18], [0x01, 0x02, 0x1b], 1, 0x0001),
+    ("SUBWF f,f", 0x02a0, [0x02, 0x01, 0x1f], [0x02, 0xff, 0x18], 1, 0x0001),
+    ("SUBWF f,f", 0x02a0, [0x10, 0x10, 0x18], [0x10, 0x00, 0x1f], 1, 0x0001),
+    ("SUBWF f,w", 0x0220, [0x01, 0x10, 0x18], [0x0f, 0x10, 0x19], 1, 0x0001),
+    ("SWAPF f,f", 0x0ea0, [0x00, 0xa5, 0x1f], [0x00, 0x5a, 0x1f], 1, 0x0001),
+    ("SWAPF f,w", 0x0e20, [0x12, 0x00, 0x18], [0x00, 0x00, 0x18], 1, 0x0001),
+    ("XORWF f,w", 0x0620, [0xff, 0xff, 0x18], [0x00, 0xff, 0x1c], 1, 0x0001),
+    ("XORWF f,f", 0x06a0, [0x0f, 0xff, 0x1c], [0x0f, 0xf0, 0x18], 1, 0x0001),
+    ("BCF f,7", 0x13a0, [0x00, 0xff, 0x1f], [0x00, 0x7f, 0x1f], 1, 0x0001),
+    ("BSF f,0", 0x1420, [0x00, 0x00, 0x18], [0x00, 0x01, 0x18], 1, 0x0001),
+    ("BTFSC f,3", 0x19a0, [0x00, 0x08, 0x18], [0x00, 0x08, 0x18], 1, 0x0001),
+    ("BTFSC f,3", 0x19a0, [0x00, 0x00, 0x18], [0x00, 0x00, 0x18], 2, 0x0002),
+    ("BTFSS f,3", 0x1da0, [0x00, 0x08, 0x18], [0x00, 0x08, 0x18], 2, 0x0002),
+    ("BTFSS f,3", 0x1da0, [0x00, 0x00, 0x18], [0x00, 0x00, 0x18], 1, 0x0001),
+    ("ADDLW 01h", 0x3e01, [0xff, 0x00, 0x18], [0x00, 0x00, 0x1f], 1, 0x0001),
+    ("ADDLW 22h", 0x3e22, [0x11, 0x00, 0x1f], [0x33, 0x00, 0x18], 1, 0x0001),
+    ("ANDLW 0Fh", 0x390f, [0xf0, 0x00, 0x18], [0x00, 0x00, 0x1c], 1, 0x0001),
+    ("CALL 123h", 0x2123, [0x00, 0x00, 0x18], [0x00, 0x00, 0x18], 2, 0x0123),
+    ("CLRWDT", 0x0064, [0x00, 0x00, 0x07], [0x00, 0x00, 0x1f], 1, 0x0001),
+    ("GOTO 45h", 0x2845, [0x00, 0x00, 0x18], [0x00, 0x00, 0x18], 2, 0x0045),
+    ("IORLW 00h", 0x3800, [0x00, 0x00, 0x18], [0x00, 0x00, 0x1c], 1, 0x0001),
+    ("MOVLW ABh", 0x30ab, [0x00, 0x00, 0x1f], [0xab, 0x00, 0x1f], 1, 0x0001),
+    ("RETFIE", 0x0009, [0x00, 0x00, 0x18], [0x00, 0x00, 0x18], 2, RETURN_ADDRESS),
+    ("RETLW 42h", 0x3442, [0x00, 0x00, 0x18], [0x42, 0x00, 0x18], 2, RETURN_ADDRESS),
+    ("RETURN", 0x0008, [0x00, 0x00, 0x18], [0x00, 0x00, 0x18], 2, RETURN_ADDRESS),
+    ("SLEEP", 0x0063, [0x00, 0x00, 0x18], [0x00, 0x00, 0x10], 1, 0x0001),
+    ("SUBLW 10h", 0x3c10, [0x01, 0x00, 0x18], [0x0f, 0x00, 0x19], 1, 0x0001),
+    ("SUBLW 01h", 0x3c01, [0x02, 0x00, 0x1f], [0xff, 0x00, 0x18], 1, 0x0001),
+    ("XORLW FFh", 0x3aff, [0xff, 0x00, 0x18], [0x00, 0x00, 0x1c], 1, 0x0001),
+];
+
+fn cpu_with(opcode: u16, [w, f, status]: [u8; 3]) -> Cpu {
+    let mut cpu = Cpu::new();
+
+    let mut program = ParseResult::new();
+    program.program = opcode.to_be_bytes().to_vec();
+    cpu.rom_bus.load_program(&program);
+    cpu.data_bus.sfr_bank.w = w;
+    cpu.data_bus.sfr_bank.status = status;
+    cpu.data_bus.memory[F as usize] = f;
+    let _ = cpu.data_bus.stack.push(RETURN_ADDRESS);
+    cpu
+}
+
+#[test]
+fn instruction_set_conformance() {
+    let mut failures = Vec::new();
+
+    for &(name, opcode, before, after, cycles, pc) in CASES {
+        let mut cpu = cpu_with(opcode, before);
+        cpu.step();
+
+        let sfr_bank = &cpu.data_bus.sfr_bank;
+        let actual = [sfr_bank.w, cpu.data_bus.memory[F as usize], sfr_bank.status];
+
+        if actual != after || cpu.cycles != cycles || cpu.data_bus.get_pc() != pc {
+            failures.push(format!(
+                "{} ({:04x}): expected {:02x?} in {} cycles to {:04x}, got {:02x?} in {} cycles to {:04x}",
+                name,
+                opcode,
+                after,
+                cycles,
+                pc,
+                actual,
+                cpu.cycles,
+                cpu.data_bus.get_pc()
+            ));
+        }
+    }
+
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn call_pushes_return_address() {
+    let mut cpu = cpu_with(0x2123, [0x00, 0x00, 0x18]);
+    cpu.step();
+
+    assert_eq!(cpu.data_bus.stack.entries(), vec![RETURN_ADDRESS, 0x0001]);
+}
+
+#[test]
+fn retfie_sets_gie() {
+    let mut cpu = cpu_with(0x0009, [0x00, 0x00, 0x18]);
+    cpu.step();
+
+    assert!(get_bit(cpu.data_bus.sfr_bank.intcon, GIE));
+    assert!(cpu.data_bus.stack.entries().is_empty());
+}
+
+#[test]
+fn sleep_stops_the_cpu() {
+    let mut cpu = cpu_with(0x0063, [0x00, 0x00, 0x18]);
+    cpu.step();
+
+    assert!(cpu.sleeping);
+}
+
+#[test]
+fn status_destination_keeps_flags() {
+    // CLRF STATUS leaves 000u u1uu, TO and PD are read-only
+    let mut cpu = cpu_with(0x0183, [0x00, 0x00, 0xfb]);
+    cpu.step();
+    assert_eq!(cpu.data_bus.sfr_bank.status, 0x1f);
+
+    // MOVWF STATUS writes all bits except TO and PD
+    let mut cpu = cpu_with(0x0083, [0xe0, 0x00, 0x1f]);
+    cpu.step();
+    assert_eq!(cpu.data_bus.sfr_bank.status, 0xf8);
+}
+
+#[test]
+fn indirect_access_through_fsr() {
+    // INCF INDF,f increments the register FSR points to
+    let mut cpu = cpu_with(0x0a80, [0x00, 0x41, 0x18]);
+    cpu.data_bus.sfr_bank.fsr = F;
+    cpu.step();
+    assert_eq!(cpu.data_bus.memory[F as usize], 0x42);
+
+    // With FSR=0 INDF reads as 0 and writes are ignored
+    let mut cpu = cpu_with(0x0a80, [0x00, 0x41, 0x18]);
+    cpu.data_bus.sfr_bank.fsr = 0;
+    cpu.step();
+    assert_eq!(cpu.data_bus.read_byte(0x00), 0x00);
+    assert_eq!(cpu.data_bus.sfr_bank.status, 0x18);
+}
+
+#[test]
+fn pull_ups_raise_floating_inputs() {
+    let mut cpu = Cpu::new();
+    assert_eq!(cpu.data_bus.port_b.port.pins(), 0x00);
+
+    // Clearing RBPU enables the pull-ups, a pin driven low from outside stays low
+    clear_bit(&mut cpu.data_bus.sfr_bank.option, RBPU);
+    cpu.data_bus.update_pins();
+    assert_eq!(cpu.data_bus.port_b.port.pins(), 0xff);
+
+    cpu.data_bus.set_portb_pin(RB0, false);
+    assert_eq!(cpu.data_bus.port_b.port.pins(), 0xfe);
+}
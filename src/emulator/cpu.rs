@@ -1,298 +1,520 @@
+use super::alu;
+use super::breakpoint::*;
 use super::data_bus::*;
+use super::eeprom::Eeprom;
+use super::event::*;
 use super::instruction::*;
+use super::interrupt::*;
 use super::rom_bus::*;
+use super::stack::*;
+use super::timer::*;
 use super::bits::*;
-use std::sync::mpsc::{Receiver, Sender};
-use std::time::{Instant, Duration};
-use crate::emulator::{parse_lst_file, ParseResult};
+use super::watchdog::Watchdog;
+use std::time::Duration;
+use crate::emulator::{ParseResult, ProgramInfo};
 use std::fs;
 use std::path::Path;
-use std::panic::resume_unwind;
-use std::alloc::dealloc;
 
-pub struct CPU {
+// The emulated pic16f84 without any frontend, changes are reported to the observers
+pub struct Cpu {
     pub cycles: usize,
     pub data_bus: DataBus,
     pub rom_bus: RomBus,
-    pub input: Receiver<Vec<String>>,
-    pub output: Sender<Vec<String>>,
-    pub running: bool,
-    program_info: ParseResult,
-    last: Instant,
-    now: Instant,
+    pub sleeping: bool,
+    // Reports stack overflows and underflows instead of wrapping silently
+    pub strict_stack: bool,
+    // One instruction cycle takes four oscillator periods
+    pub cycle_duration: Duration,
+    // Address breakpoints, the watchpoints are part of the data bus
+    pub breakpoints: Breakpoints,
+    reported_ports: Option<[u8; 4]>,
+    program_info: ProgramInfo,
     jump_performed: bool,
-    frame_duration: Duration,
-    output_duration: Duration,
-    commands: Vec<String>,
+    observers: Vec<Box<dyn Observer + Send>>,
 }
 
-impl CPU {
-    pub fn new(input: Receiver<Vec<String>>, output: Sender<Vec<String>>) -> Self {
+impl Cpu {
+    pub fn new() -> Self {
         Self {
             cycles: 0,
             data_bus: DataBus::new(),
             rom_bus: RomBus::new(),
-            input,
-            output,
             jump_performed: false,
-            commands: vec![],
-            last: Instant::now(),
-            now: Instant::now(),
-            frame_duration: Duration::from_millis(100),
-            // TODO: use this
-            output_duration: Duration::from_millis(500),
-            program_info: ParseResult::new(),
-            running: false,
+            observers: vec![],
+            // 4 MHz
+            cycle_duration: Duration::from_micros(1),
+            program_info: ProgramInfo::new(),
+            sleeping: false,
+            strict_stack: false,
+            breakpoints: Breakpoints::new(),
+            reported_ports: None,
         }
     }
 
-    pub fn reset(&mut self) {
-        self.commands.clear();
+    // Replaces the program memory and performs a power-on reset
+    pub fn load(&mut self, program: ParseResult) {
+        self.rom_bus.load_program(&program);
+        if let Some(eeprom) = program.eeprom {
+            self.data_bus.eeprom.data = eeprom;
+        }
+
+        self.program_info = program.info;
+        self.reset(ResetKind::PowerOn);
+    }
 
-        if !self.program_info.pc_mapper.is_empty() {
-            self.write_command(format!("RESLINE {}", self.program_info.pc_mapper.get(&self.data_bus.get_pc()).unwrap()));
+    pub fn load_eeprom(&mut self, path: &Path) -> Result<(), String> {
+        let content = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        self.data_bus.eeprom.load_image(&content)
+    }
+
+    // Saves the eeprom as Intel HEX if the file ends with .hex, otherwise as raw binary
+    pub fn save_eeprom(&self, path: &Path) -> Result<(), String> {
+        let is_hex = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("hex"));
+        let content = if is_hex {
+            self.data_bus.eeprom.to_hex().into_bytes()
+        } else {
+            self.data_bus.eeprom.to_binary()
+        };
+
+        fs::write(path, content).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+
+    pub fn reset(&mut self, kind: ResetKind) {
+        if let Some(line) = self.program_info.line_of(self.data_bus.get_pc()) {
+            self.emit(SimEvent::LineLeft(line));
         }
 
         self.cycles = 0;
-        self.data_bus = DataBus::new();
+        self.data_bus.reset(kind);
         self.jump_performed = false;
-        self.data_bus.load_pc(0);
+        self.sleeping = false;
+        self.reported_ports = None;
+
+        self.emit(SimEvent::Reset(kind));
+        self.output_pc();
+        self.emit(SimEvent::WChanged(self.data_bus.sfr_bank.w));
+        self.output_status();
+        self.emit(SimEvent::FsrChanged(self.data_bus.sfr_bank.fsr));
+        self.emit(SimEvent::OptionChanged(self.data_bus.sfr_bank.option));
+        self.output_timer0();
+        self.output_stack();
 
-        self.write_command(format!("PCL {:02x}h", self.data_bus.sfr_bank.pcl));
-        self.write_command(format!("PCLATH {:02x}h", self.data_bus.sfr_bank.pclath));
-        self.write_command(format!("PCINTERN {:04}", self.data_bus.get_pc()));
-        self.write_command(format!("WREG {:02x}h", self.data_bus.sfr_bank.w));
-        self.write_command(format!("STATUS {:02x}h", self.data_bus.sfr_bank.status));
-        self.write_command(format!("FSR {:02x}h", self.data_bus.sfr_bank.fsr));
-        self.write_command(format!("OPTION {:02x}h", self.data_bus.sfr_bank.option));
-        self.write_command(format!("TIMER0 {:02x}h", self.data_bus.sfr_bank.tmr0));
-        self.write_command(String::from("STACK"));
+        if let Some(line) = self.program_info.line_of(0) {
+            self.emit(SimEvent::LineEntered(line));
+        }
     }
 
-    fn write_command(&mut self, cmd: String) {
-        self.commands.push(cmd);
+    pub fn add_observer(&mut self, observer: Box<dyn Observer + Send>) {
+        self.observers.push(observer);
     }
 
-    pub fn update(&mut self) {
-        self.now = Instant::now();
+    fn emit(&mut self, event: SimEvent) {
+        for observer in self.observers.iter_mut() {
+            observer.on_event(&event);
+        }
+    }
 
-        let work_time = self.now - self.last;
+    // Steps until the condition holds or max_cycles have passed and returns whether the condition
+    // was met. A breakpoint, watchpoint or opcode which can not be fetched stops with its reason.
+    pub fn run_until<F>(&mut self, max_cycles: usize, mut condition: F) -> Result<bool, BreakReason>
+    where
+        F: FnMut(&Cpu) -> bool,
+    {
+        let start = self.cycles;
 
-        if work_time < self.frame_duration {
-            let delta = self.frame_duration - work_time;
-            std::thread::sleep(delta);
-        }
+        // A reset starts counting the cycles from 0 again
+        while self.cycles.saturating_sub(start) < max_cycles {
+            if let Some(reason) = self.step() {
+                return Err(reason);
+            }
 
-        self.last = Instant::now();
-
-        match self.input.try_recv() {
-            Ok(data) => {
-                for command in &data {
-                    println!("{}", command);
-
-                    if command.starts_with("C:\\") {
-                        println!("Loading file: {}", command);
-                        let content = fs::read_to_string(command).expect("Failed to open file");
-                        let result = parse_lst_file(content.as_str());
-
-                        self.rom_bus.load_program(&result.program, 0);
-                        self.reset();
-
-                        self.write_command(format!("SETLINE {}", result.pc_mapper.get(&0).unwrap()));
-                        self.program_info = result;
-
-                        println!("Finished loading file");
-                    } else {
-                        let tokens: Vec<&str> = command.split(" ").collect();
-                        match tokens[0] {
-                            "STEP" => self.step(),
-                            "RESET" => {
-                                self.reset();
-                                self.write_command(format!("SETLINE {}", self.program_info.pc_mapper.get(&0).unwrap()))
-                            },
-                            "START" => self.running = true,
-                            "STOPP" => self.running = false,
-                            "XTAL" => {
-                                let f_base = tokens[1].parse::<usize>().unwrap();
-                                let f_mul = match tokens[2] {
-                                    "kHz" => 1000,
-                                    "MHz" => 1000000,
-                                    _ => panic!("oopsie")
-                                };
-
-                                self.frame_duration = Duration::from_nanos(hertz::fps_to_ns_per_frame(f_base * f_mul));
-                            }
-                            "PORTA" => {
-                                let tmp: Vec<&str> = tokens[1].split(",").collect();
-                                let idx = tmp[0].parse::<usize>().unwrap();
-                                let bit = tmp[1].parse::<u8>().unwrap();
-
-                                if bit != 0 {
-                                    self.set_fsr_bit(PORTA_ADDR, idx);
-                                } else {
-                                    self.clear_fsr_bit(PORTA_ADDR, idx);
-                                }
-                            }
-                            "PORTB" => {
-                                let tmp: Vec<&str> = tokens[1].split(",").collect();
-                                let idx = tmp[0].parse::<usize>().unwrap();
-                                let bit = tmp[1].parse::<u8>().unwrap();
-
-                                if bit != 0 {
-                                    self.set_fsr_bit(PORTB_ADDR, idx);
-                                } else {
-                                    self.clear_fsr_bit(PORTB_ADDR, idx);
-                                }
-                            }
-                            _ => println!("Unknown input command: {}", command)
-                        };
-                    }
-                }
+            if condition(self) {
+                return Ok(true);
             }
-            _ => {}
         }
 
-        if self.running {
-            self.step();
-        }
+        Ok(false)
+    }
+
+    // Accessors
+    pub fn program_info(&self) -> &ProgramInfo { &self.program_info }
+    pub fn w(&self) -> u8 { self.data_bus.sfr_bank.w }
+    pub fn status(&self) -> u8 { self.data_bus.sfr_bank.status }
+    pub fn pc(&self) -> u16 { self.data_bus.get_pc() }
+    // Reads a register file address (0x00-0xff) without the side effects of a program read
+    pub fn register(&self, address: u8) -> u8 { self.data_bus.peek_byte(address) }
+    // The general purpose registers 0x0c-0x4f
+    pub fn ram(&self) -> &[u8] { &self.data_bus.memory[GPR_START as usize..=GPR_END as usize] }
+    pub fn stack(&self) -> Vec<u16> { self.data_bus.stack.entries() }
+    pub fn eeprom(&self) -> &Eeprom { &self.data_bus.eeprom }
+    pub fn watchdog(&self) -> &Watchdog { &self.data_bus.watchdog }
+    pub fn porta_pins(&self) -> u8 { self.data_bus.port_a.pins() }
+    pub fn portb_pins(&self) -> u8 { self.data_bus.port_b.port.pins() }
+
+    // Applies a level to a pin from outside, RA4 also clocks timer0
+    pub fn set_porta_pin(&mut self, bit: usize, level: bool) {
+        self.data_bus.set_porta_pin(bit, level);
+        self.output_ports();
+        self.output_timer0();
+    }
+
+    // Applies a level to a pin from outside, RB0 and RB4-RB7 may raise interrupt flags
+    pub fn set_portb_pin(&mut self, bit: usize, level: bool) {
+        self.data_bus.set_portb_pin(bit, level);
+        self.output_ports();
+        self.output_intcon();
+    }
+
+    // Writes a register file address (0x00-0xff) like the program would
+    pub fn write_register(&mut self, address: u8, value: u8) {
+        self.data_bus.write_byte(address, value);
+        self.emit(SimEvent::RegisterChanged { address, value: self.register(address) });
+        self.output_ports();
+        self.output_intcon();
+    }
 
-        if !self.commands.is_empty() {
-            self.output.send(self.commands.clone());
-            self.commands.clear();
+    // Continues the program at another address, like a goto without PCLATH
+    pub fn set_pc(&mut self, pc: u16) {
+        if let Some(line) = self.program_info.line_of(self.pc()) {
+            self.emit(SimEvent::LineLeft(line));
         }
+        self.data_bus.set_pc(pc);
+        if let Some(line) = self.program_info.line_of(self.pc()) {
+            self.emit(SimEvent::LineEntered(line));
+        }
+        self.output_pc();
     }
 
-    pub fn step(&mut self) {
+    // Executes one instruction, returns why the cpu has to stop if a breakpoint or watchpoint
+    // was hit or the instruction could not be fetched
+    pub fn step(&mut self) -> Option<BreakReason> {
+        if self.sleeping {
+            self.sleep_cycle();
+            return None;
+        }
+
         let old_pc = self.data_bus.get_pc();
-        let result = self.rom_bus.read_instruction(old_pc);
+        let instr = match self.rom_bus.read_instruction(old_pc) {
+            Ok(instr) => instr,
+            Err(error) => {
+                // Nothing is executed and no time passes, so the cpu is stuck at the pc
+                warn!("{}", error);
+                let reason = BreakReason::InvalidOpcode { address: old_pc, error };
+                self.emit(SimEvent::BreakpointHit(reason.clone()));
+                return Some(reason);
+            }
+        };
 
-        if let Ok(instr) = result {
-            debug!("Executing {:?}", instr);
-            self.execute(instr);
-        } else {
-            println!("{}", result.err().unwrap());
-            return;
-        }
+        // The pc is incremented during the fetch, so the
+        // instruction already sees the address of its successor
+        self.data_bus.inc_pc(1);
+        self.data_bus.pcl_written = false;
+
+        debug!("Executing {:?}", instr);
+        // Only accesses of the program count, not those of the gui or the debugger
+        self.data_bus.watchpoints.arm(true);
+        self.execute(instr);
+        self.data_bus.watchpoints.arm(false);
 
         // If jump was performed one additional cycle has to be added
-        self.cycles += if self.jump_performed {
+        let cycles = if self.jump_performed || self.data_bus.pcl_written {
             2
         } else {
-            self.data_bus.inc_pc(1);
             1
         };
 
-        self.write_command(format!("RESLINE {}", self.program_info.pc_mapper.get(&old_pc).unwrap()));
-        self.write_command(format!("SETLINE {}", self.program_info.pc_mapper.get(&self.data_bus.get_pc()).unwrap()));
-        self.write_command(format!("PCL {:02x}h", self.data_bus.sfr_bank.pcl));
-        self.write_command(format!("PCLATH {:02x}h", self.data_bus.sfr_bank.pclath));
-        self.write_command(format!("PCINTERN {:04}", self.data_bus.get_pc()));
+        self.cycles += cycles;
+        self.tick(cycles);
+
+        if interrupt_requested(&self.data_bus.sfr_bank) {
+            self.interrupt();
+        }
+
+        // Opcodes without a listing line (e.g. after a computed jump) have no line to mark
+        if let Some(line) = self.program_info.line_of(old_pc) {
+            self.emit(SimEvent::LineLeft(line));
+        }
+        if let Some(line) = self.program_info.line_of(self.data_bus.get_pc()) {
+            self.emit(SimEvent::LineEntered(line));
+        }
+        self.output_pc();
+
+        if self.ports_changed() {
+            self.output_ports();
+        }
+
+        let reason = self.data_bus.watchpoints.take_hit().or_else(|| self.check_breakpoint());
+        if let Some(reason) = &reason {
+            self.emit(SimEvent::BreakpointHit(reason.clone()));
+        }
+        reason
+    }
+
+    fn check_breakpoint(&mut self) -> Option<BreakReason> {
+        let pc = self.pc();
+        let breakpoint = self.breakpoints.get(pc)?;
+
+        if breakpoint.condition.as_ref().is_none_or(|condition| condition.evaluate(self)) {
+            self.breakpoints.hit(pc)
+        } else {
+            None
+        }
+    }
+
+    fn ports_snapshot(&self) -> [u8; 4] {
+        let sfr_bank = &self.data_bus.sfr_bank;
+        [
+            self.data_bus.port_a.pins(),
+            self.data_bus.port_b.port.pins(),
+            sfr_bank.trisa,
+            sfr_bank.trisb,
+        ]
+    }
+
+    fn ports_changed(&self) -> bool {
+        self.reported_ports != Some(self.ports_snapshot())
+    }
+
+    fn output_ports(&mut self) {
+        let ports = self.ports_snapshot();
+        self.reported_ports = Some(ports);
+
+        for (port, pins, tris, width) in [(PortId::A, ports[0], ports[2], 5), (PortId::B, ports[1], ports[3], 8)] {
+            for bit in 0..width {
+                self.emit(SimEvent::PinChanged { port, bit, level: get_bit(pins, bit) });
+            }
+            for bit in 0..width {
+                self.emit(SimEvent::DirectionChanged { port, bit, input: get_bit(tris, bit) });
+            }
+        }
+
+        self.emit(SimEvent::RegisterChanged { address: PORTA_ADDR, value: ports[0] });
+        self.emit(SimEvent::RegisterChanged { address: PORTB_ADDR, value: ports[1] });
+    }
+
+    fn output_pc(&mut self) {
+        let sfr_bank = &self.data_bus.sfr_bank;
+        let event = SimEvent::PcChanged { pc: self.data_bus.get_pc(), pcl: sfr_bank.pcl, pclath: sfr_bank.pclath };
+        self.emit(event);
+    }
+
+    fn interrupt(&mut self) {
+        debug!("Entering interrupt service routine");
+
+        // Vectoring behaves like a call to the interrupt vector
+        let return_address = self.data_bus.get_pc();
+        self.emit(SimEvent::InterruptEntered { return_address });
+        self.push(return_address);
+        clear_bit(&mut self.data_bus.sfr_bank.intcon, GIE);
+        self.data_bus.set_pc(INTERRUPT_VECTOR);
+
+        self.cycles += 2;
+        self.tick(2);
+    }
+
+    fn tick(&mut self, cycles: usize) {
+        for _ in 0..cycles {
+            // The oscillator is stopped during sleep
+            if !self.sleeping {
+                self.data_bus.tick();
+            }
+
+            self.data_bus.tick_eeprom(self.cycle_duration);
+
+            if self.rom_bus.watchdog_enabled() && self.data_bus.tick_watchdog(self.cycle_duration) {
+                self.watchdog_timeout();
+            }
+        }
+
+        self.output_timer0();
+        self.output_watchdog();
+    }
+
+    fn watchdog_timeout(&mut self) {
+        if self.sleeping {
+            info!("Watchdog timeout, waking up cpu");
+
+            clear_bit(&mut self.data_bus.sfr_bank.status, TO);
+            self.wake_up();
+        } else {
+            info!("Watchdog timeout, resetting cpu");
+
+            self.data_bus.reset(ResetKind::Watchdog);
+            self.emit(SimEvent::Reset(ResetKind::Watchdog));
+            self.output_status();
+            self.output_intcon();
+            self.emit(SimEvent::OptionChanged(self.data_bus.sfr_bank.option));
+        }
+    }
+
+    // Instruction fetching is stopped during sleep, only the watchdog keeps running
+    fn sleep_cycle(&mut self) {
+        self.cycles += 1;
+        self.tick(1);
+
+        // Any enabled interrupt wakes the cpu regardless of GIE. The instruction
+        // after SLEEP is executed before the cpu vectors to the interrupt routine.
+        if self.sleeping && interrupt_pending(&self.data_bus.sfr_bank) {
+            info!("Interrupt, waking up cpu");
+            self.wake_up();
+        }
+    }
+
+    fn wake_up(&mut self) {
+        self.sleeping = false;
+        self.emit(SimEvent::WokeUp);
+        self.output_status();
+    }
+
+    fn output_watchdog(&mut self) {
+        let option = self.data_bus.sfr_bank.option;
+        let ratio = if Prescaler::assigned_to_wdt(option) {
+            Prescaler::wdt_ratio(option)
+        } else {
+            Prescaler::tmr0_ratio(option)
+        };
+
+        self.emit(SimEvent::PrescalerChanged { ratio });
+
+        let remaining = if self.rom_bus.watchdog_enabled() {
+            Some(self.data_bus.watchdog.remaining())
+        } else {
+            None
+        };
+        self.emit(SimEvent::WatchdogChanged { remaining });
+    }
+
+    fn output_status(&mut self) {
+        self.emit(SimEvent::StatusChanged(self.get_status()));
+    }
+
+    fn output_timer0(&mut self) {
+        self.emit(SimEvent::Timer0Changed(self.data_bus.sfr_bank.tmr0));
+        self.output_intcon();
+    }
+
+    fn output_intcon(&mut self) {
+        self.emit(SimEvent::IntconChanged(self.data_bus.sfr_bank.intcon));
     }
 
     // Getter methods
     // Flags
     fn get_carry(&self) -> bool { get_bit(self.data_bus.sfr_bank.status, C) }
-    fn get_digit_carry(&self) -> bool { get_bit(self.data_bus.sfr_bank.status, DC) }
-    fn get_zero(&self) -> bool { get_bit(self.data_bus.sfr_bank.status, Z) }
     // Register
     fn get_w(&self) -> u8 { self.data_bus.sfr_bank.w }
     fn get_status(&self) -> u8 { self.data_bus.sfr_bank.status }
-    fn get_fsr(&mut self, mut destination: u8) -> u8 {
-        self.data_bus.read_byte(if destination == INDIRECT_ADDR { self.data_bus.sfr_bank.fsr } else { destination })
+    fn get_fsr(&mut self, destination: u8) -> u8 {
+        let real_addr = self.get_sfr_address(destination);
+        self.data_bus.read_byte(real_addr)
     }
     fn get_fsr_bit(&mut self, destination: u8, index: usize) -> bool {
-        self.data_bus.get_bit(if destination == INDIRECT_ADDR { self.data_bus.sfr_bank.fsr } else { destination }, index)
+        let real_addr = self.get_sfr_address(destination);
+        self.data_bus.get_bit(real_addr, index)
     }
 
     // Setter methods
     fn set_zero(&mut self, value: bool) {
         set_bit_enabled(&mut self.data_bus.sfr_bank.status, Z, value);
-        self.write_command(format!("STATUSBIT {},{}", Z, value as u8));
-        self.write_command(format!("STATUS {:02x}h", self.get_status()));
+        self.output_status();
     }
 
     fn set_carry(&mut self, value: bool) {
         set_bit_enabled(&mut self.data_bus.sfr_bank.status, C, value);
-        self.write_command(format!("STATUSBIT {},{}", C, value as u8));
-        self.write_command(format!("STATUS {:02x}h", self.get_status()));
+        self.output_status();
     }
 
     fn set_digit_carry(&mut self, value: bool) {
         set_bit_enabled(&mut self.data_bus.sfr_bank.status, DC, value);
-        self.write_command(format!("STATUSBIT {},{}", DC, value as u8));
-        self.write_command(format!("STATUS {:02x}h", self.get_status()));
+        self.output_status();
     }
 
-    fn set_w(&mut self, value: u8) {
+    pub fn set_w(&mut self, value: u8) {
         self.data_bus.sfr_bank.w = value;
-        self.write_command(format!("WREG {:02x}h", value));
+        self.emit(SimEvent::WChanged(value));
     }
 
     fn get_sfr_address(&mut self, destination: u8) -> u8 {
-        if destination == INDIRECT_ADDR {
-            self.data_bus.sfr_bank.fsr
-        } else {
-            destination
-        }
+        self.data_bus.file_address(destination)
     }
 
-    fn set_fsr(&mut self, mut destination: u8, value: u8, dflag: bool) {
+    fn set_fsr(&mut self, destination: u8, value: u8, dflag: bool) {
         if !dflag {
             self.set_w(value);
         } else {
             let real_addr = self.get_sfr_address(destination);
             self.data_bus.write_byte(real_addr, value);
-            self.write_command(format!("FREG {},0x{:02x}", real_addr, value));
+            self.emit(SimEvent::RegisterChanged { address: real_addr, value });
         }
     }
 
+    // Instructions affecting Z, DC or C cannot write these bits when STATUS is the destination
+    fn set_fsr_with_flags(&mut self, destination: u8, value: u8, dflag: bool) {
+        let flags = (1 << Z) | (1 << DC) | (1 << C);
+        let value = if dflag && self.get_sfr_address(destination) & 0x7f == STATUS_ADDR {
+            (value & !flags) | (self.get_status() & flags)
+        } else {
+            value
+        };
+
+        self.set_fsr(destination, value, dflag);
+    }
+
     fn set_fsr_bit(&mut self, destination: u8, index: usize) {
         let real_addr = self.get_sfr_address(destination);
         self.data_bus.set_bit(real_addr, index);
-        let val = self.get_fsr(real_addr);
-        self.write_command(format!("FREG {},0x{:02x}", real_addr, val));
+        let val = self.data_bus.peek_byte(real_addr);
+        self.emit(SimEvent::RegisterChanged { address: real_addr, value: val });
     }
 
     fn clear_fsr_bit(&mut self, destination: u8, index: usize) {
         let real_addr = self.get_sfr_address(destination);
         self.data_bus.clear_bit(real_addr, index);
-        let val = self.get_fsr(real_addr);
-        self.write_command(format!("FREG {},0x{:02x}", real_addr, val));
+        let val = self.data_bus.peek_byte(real_addr);
+        self.emit(SimEvent::RegisterChanged { address: real_addr, value: val });
     }
 
-    pub fn output_stack(&mut self) {
-        let mut out = String::from("STACK ");
-
-        if self.data_bus.stack.len() > 0 {
-            out += &format!("{:04}", self.data_bus.stack[0]);
-        }
-
-        for i in 1..self.data_bus.stack.len() {
-            out += &format!(", {:04}", self.data_bus.stack[i]);
-        }
-
-        println!("{}", out);
-
-        self.write_command(out);
+    fn output_stack(&mut self) {
+        self.emit(SimEvent::StackChanged(self.data_bus.stack.entries()));
     }
 
     fn push(&mut self, value: u16) {
-        self.data_bus.stack.push(value);
+        let result = self.data_bus.stack.push(value);
+        self.check_stack(result, value);
         self.output_stack();
     }
 
     fn pop(&mut self) -> u16 {
-        let ret = self.data_bus.stack.pop();
+        let (value, result) = self.data_bus.stack.pop();
+        self.check_stack(result, value);
         self.output_stack();
-        ret.unwrap()
+        value
     }
 
-    // Checker functions
-    fn check_digit_carry(&self, a: u8, b: u8) -> bool { ((a & 0xf) + (b & 0xf)) > 0xf }
+    // The hardware stack wraps silently, in strict mode the observers are told about it
+    fn check_stack(&mut self, result: Result<(), StackError>, value: u16) {
+        if let Err(error) = result {
+            warn!("Stack {:?} with return address {:04x}h", error, value);
+
+            if self.strict_stack {
+                self.emit(SimEvent::StackError { error, address: value });
+            }
+        }
+    }
+
+    // The skipped instruction is executed as a NOP, so a skip takes two cycles
+    fn skip(&mut self) {
+        self.data_bus.inc_pc(1);
+        self.jump_performed = true;
+    }
+
+    fn set_alu_flags(&mut self, result: alu::AluResult) {
+        self.set_zero(result.zero);
+        self.set_carry(result.carry);
+        self.set_digit_carry(result.digit_carry);
+    }
 
     fn execute(&mut self, instruction: Instruction) {
         self.jump_performed = false;
-        // TODO: Implement instructions
 
         match instruction {
             Instruction::Nop => {},
@@ -310,13 +532,9 @@ impl CPU {
                 self.set_w(val);
             }
             Instruction::AddLw(Literal(value)) => {
-                let (result, carry) = self.get_w().overflowing_add(value);
-
-                self.set_zero(result == 0);
-                self.set_carry(carry);
-                self.set_digit_carry(self.check_digit_carry(self.get_w(), value));
-
-                self.set_w(result);
+                let result = alu::add(self.get_w(), value);
+                self.set_w(result.value);
+                self.set_alu_flags(result);
             }
             Instruction::Goto(Address(idx)) => {
                 self.data_bus.load_pc(idx);
@@ -332,13 +550,10 @@ impl CPU {
                 self.clear_fsr_bit(destination, idx);
             }
             Instruction::SubLw(Literal(value)) => {
-                let val = value.wrapping_sub(self.get_w());
-
-                self.set_zero(val == 0);
-                self.set_carry(val >= 0);
-                self.set_digit_carry(self.check_digit_carry(value, self.get_w()));
-
-                self.set_w(val);
+                // SUBLW computes k - W
+                let result = alu::sub(value, self.get_w());
+                self.set_w(result.value);
+                self.set_alu_flags(result);
             }
             Instruction::XorLw(Literal(value)) => {
                 let val = value ^ self.get_w();
@@ -347,70 +562,86 @@ impl CPU {
             }
             Instruction::Call(Address(idx)) => {
                 self.push(self.data_bus.get_pc());
-                self.data_bus.load_pc(idx - 1);
+                self.data_bus.load_pc(idx);
+                self.jump_performed = true
             }
             Instruction::Return => {
                 let pc = self.pop();
-                self.data_bus.load_pc(pc);
+                self.data_bus.set_pc(pc);
+                self.jump_performed = true
             }
             Instruction::RetLw(Literal(value)) => {
                 self.set_w(value);
                 let pc = self.pop();
-                self.data_bus.load_pc(pc);
+                self.data_bus.set_pc(pc);
+                self.jump_performed = true
             }
-            Instruction::AddWf(FileRegister(destination), DestinationFlag(dflag)) => {
-                let (result, carry) = self.get_w().overflowing_add(self.get_fsr(destination));
-
-                self.set_zero(result == 0);
-                self.set_carry(carry);
-                let (w, fsr) = (self.get_w(), self.get_fsr(destination));
-                let dc = self.check_digit_carry(w, fsr);
-                self.set_digit_carry(dc);
+            Instruction::ClearWdt => {
+                self.data_bus.clear_watchdog();
+                self.output_status();
+            }
+            Instruction::Sleep => {
+                // A pending interrupt turns SLEEP into a NOP
+                if !interrupt_pending(&self.data_bus.sfr_bank) {
+                    self.data_bus.clear_watchdog();
+                    clear_bit(&mut self.data_bus.sfr_bank.status, PD);
+                    self.sleeping = true;
 
-                self.set_fsr(destination, result, dflag);
+                    self.emit(SimEvent::Halted);
+                    self.output_status();
+                }
+            }
+            Instruction::RetFie => {
+                let pc = self.pop();
+                self.data_bus.set_pc(pc);
+                set_bit(&mut self.data_bus.sfr_bank.intcon, GIE);
+                self.output_intcon();
+                self.jump_performed = true
+            }
+            Instruction::AddWf(FileRegister(destination), DestinationFlag(dflag)) => {
+                let result = alu::add(self.get_w(), self.get_fsr(destination));
+                self.set_fsr_with_flags(destination, result.value, dflag);
+                self.set_alu_flags(result);
             }
             Instruction::ClrF(FileRegister(destination)) => {
-                self.set_fsr(destination, 0, true);
-                self.set_zero(false);
+                self.set_fsr_with_flags(destination, 0, true);
+                self.set_zero(true);
             }
             Instruction::ComF(FileRegister(destination), DestinationFlag(dflag)) => {
                 let val = !self.get_fsr(destination);
+                self.set_fsr_with_flags(destination, val, dflag);
                 self.set_zero(val == 0);
-                self.set_fsr(destination, val, dflag);
             }
             Instruction::AndWf(FileRegister(destination), DestinationFlag(dflag)) => {
                 let val = self.get_w() & self.get_fsr(destination);
+                self.set_fsr_with_flags(destination, val, dflag);
                 self.set_zero(val == 0);
-                self.set_fsr(destination, val, dflag);
             }
             Instruction::DecF(FileRegister(destination), DestinationFlag(dflag)) => {
                 let val = self.get_fsr(destination).wrapping_sub(1);
+                self.set_fsr_with_flags(destination, val, dflag);
                 self.set_zero(val == 0);
-                self.set_fsr(destination, val, dflag);
             }
             Instruction::IncF(FileRegister(destination), DestinationFlag(dflag)) => {
                 let val = self.get_fsr(destination).wrapping_add(1);
+                self.set_fsr_with_flags(destination, val, dflag);
                 self.set_zero(val == 0);
-                self.set_fsr(destination, val, dflag);
             }
             Instruction::MovF(FileRegister(destination), DestinationFlag(dflag)) => {
                 let val = self.get_fsr(destination);
+                self.set_fsr_with_flags(destination, val, dflag);
                 self.set_zero(val == 0);
-                self.set_fsr(destination, val, dflag);
             }
             Instruction::IorWf(FileRegister(destination), DestinationFlag(dflag)) => {
                 let val = self.get_fsr(destination) | self.get_w();
+                self.set_fsr_with_flags(destination, val, dflag);
                 self.set_zero(val == 0);
-                self.set_fsr(destination, val, dflag);
             }
             Instruction::SubWf(FileRegister(destination), DestinationFlag(dflag)) => {
-                let val = self.get_fsr(destination).wrapping_sub(self.get_w());
-                self.set_zero(val == 0);
-                let dc = self.get_fsr(destination).saturating_sub(self.get_w()) > 0xf;
-                self.set_digit_carry(dc);
-                let c = self.get_fsr(destination) > self.get_w();
-                self.set_carry(c);
-                self.set_fsr(destination, val, dflag);
+                // SUBWF computes f - W
+                let result = alu::sub(self.get_fsr(destination), self.get_w());
+                self.set_fsr_with_flags(destination, result.value, dflag);
+                self.set_alu_flags(result);
             }
             Instruction::SwapWf(FileRegister(destination), DestinationFlag(dflag)) => {
                 let val = self.get_fsr(destination);
@@ -419,8 +650,8 @@ impl CPU {
             }
             Instruction::XorWf(FileRegister(destination), DestinationFlag(dflag)) => {
                 let val = self.get_w() ^ self.get_fsr(destination);
+                self.set_fsr_with_flags(destination, val, dflag);
                 self.set_zero(val == 0);
-                self.set_fsr(destination, val, dflag);
             }
             Instruction::ClrW => {
                 self.set_w(0);
@@ -428,12 +659,12 @@ impl CPU {
             }
             Instruction::BtFsc(FileRegister(destination), BitIndex(idx)) => {
                 if !self.get_fsr_bit(destination, idx) {
-                    self.data_bus.inc_pc(1);
+                    self.skip();
                 }
             }
             Instruction::BtFss(FileRegister(destination), BitIndex(idx)) => {
                 if self.get_fsr_bit(destination, idx) {
-                    self.data_bus.inc_pc(1);
+                    self.skip();
                 }
             }
             Instruction::RlF(FileRegister(destination), DestinationFlag(dflag)) => {
@@ -441,28 +672,112 @@ impl CPU {
                 let mut val = self.get_fsr(destination) as u16;
 
                 val = (val << 1) | cy;
+                self.set_fsr_with_flags(destination, val as u8, dflag);
                 self.set_carry(val > 0xff);
-                self.set_fsr(destination, val as u8, dflag);
             }
             Instruction::RrF(FileRegister(destination), DestinationFlag(dflag)) => {
                 let cy = self.get_carry() as u8;
-                let mut val = self.get_fsr(destination);
+                let val = self.get_fsr(destination);
 
                 let new_val = (cy << 7) | (val >> 1);
+                self.set_fsr_with_flags(destination, new_val, dflag);
                 self.set_carry(get_bit(val, 0));
-                self.set_fsr(destination, new_val, dflag);
             }
             Instruction::DecFsz(FileRegister(destination), DestinationFlag(dflag)) => {
                 let val = self.get_fsr(destination).wrapping_sub(1);
-                if val == 0 { self.data_bus.inc_pc(1); }
                 self.set_fsr(destination, val, dflag);
+                if val == 0 {
+                    self.skip();
+                }
             }
             Instruction::IncFsz(FileRegister(destination), DestinationFlag(dflag)) => {
                 let val = self.get_fsr(destination).wrapping_add(1);
-                if val == 0 { self.data_bus.inc_pc(1); }
                 self.set_fsr(destination, val, dflag);
+                if val == 0 {
+                    self.skip();
+                }
             }
-            _ => panic!("Unknown instruction: {:?}", instruction)
         };
     }
 }
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::parse_program;
+
+    #[test]
+    fn run_until_stops_at_an_invalid_opcode() {
+        // The pc runs off the end of the program after the nop
+        let mut cpu = Cpu::new();
+        cpu.load(parse_program("nop.asm", "  nop\n").unwrap());
+
+        match cpu.run_until(100, |_| false) {
+            Err(BreakReason::InvalidOpcode { address: 1, .. }) => {}
+            result => panic!("Expected an invalid opcode at 0001h, got {:?}", result),
+        }
+        assert_eq!(cpu.cycles, 1);
+        assert_eq!(cpu.pc(), 1);
+
+        assert!(matches!(cpu.step(), Some(BreakReason::InvalidOpcode { address: 1, .. })));
+        assert_eq!(cpu.cycles, 1);
+    }
+
+    #[test]
+    fn run_until_stops_at_breakpoints() {
+        let mut cpu = Cpu::new();
+        cpu.load(parse_program("loop.asm", "loop  incf 0ch, f\n  goto loop\n").unwrap());
+
+        assert_eq!(cpu.run_until(100, |cpu| cpu.register(0x0c) == 2), Ok(true));
+        assert_eq!(cpu.run_until(3, |_| false), Ok(false));
+
+        cpu.breakpoints.set(0x0001, None, 0);
+        assert_eq!(cpu.run_until(100, |_| false), Err(BreakReason::Breakpoint { address: 0x0001, hits: 1 }));
+        assert_eq!(cpu.pc(), 0x0001);
+    }
+
+    fn sleep_until_rb0_edge(intcon: u8) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.load(parse_program("sleep.asm", "  sleep\n  movlw 5\n  nop\n  nop\n  nop\n").unwrap());
+        cpu.set_portb_pin(0, false);
+        cpu.write_register(INTCON_ADDR, intcon);
+
+        assert_eq!(cpu.step(), None);
+        assert!(cpu.sleeping);
+        assert_eq!(cpu.step(), None);
+        assert!(cpu.sleeping);
+
+        // OPTION selects the rising edge of RB0 after reset
+        cpu.set_portb_pin(0, true);
+        assert_eq!(cpu.step(), None);
+        assert!(!cpu.sleeping);
+        cpu
+    }
+
+    #[test]
+    fn interrupts_without_gie_continue_after_sleep() {
+        let mut cpu = sleep_until_rb0_edge(1 << INTE);
+
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.w(), 5);
+        assert_eq!(cpu.pc(), 0x0002);
+        assert!(cpu.stack().is_empty());
+    }
+
+    #[test]
+    fn interrupts_with_gie_vector_after_the_next_instruction() {
+        let mut cpu = sleep_until_rb0_edge((1 << GIE) | (1 << INTE));
+
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.w(), 5);
+        assert_eq!(cpu.pc(), INTERRUPT_VECTOR);
+        assert_eq!(cpu.stack(), vec![0x0002]);
+        assert!(!get_bit(cpu.register(INTCON_ADDR), GIE));
+    }
+}
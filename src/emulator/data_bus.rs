@@ -1,4 +1,11 @@
 use super::bits::*;
+use super::breakpoint::*;
+use super::eeprom::*;
+use super::port::*;
+use super::stack::*;
+use super::timer::*;
+use super::watchdog::*;
+use std::time::Duration;
 
 pub const INDIRECT_ADDR: u8 = 0x00;
 pub const OPTION_ADDR: u8 = 0x01;
@@ -9,7 +16,7 @@ pub const FSR_ADDR: u8 = 0x04;
 pub const TRISA_ADDR: u8 = 0x05;
 pub const PORTA_ADDR: u8 = 0x05;
 pub const TRISB_ADDR: u8 = 0x06;
-pub const PORTB_ADDR: u8 = 0x07;
+pub const PORTB_ADDR: u8 = 0x06;
 pub const EECON1_ADDR: u8 = 0x08;
 pub const EEDATA_ADDR: u8 = 0x08;
 pub const EECON2_ADDR: u8 = 0x09;
@@ -17,15 +24,43 @@ pub const EEADR_ADDR: u8 = 0x09;
 pub const PCLATH_ADDR: u8 = 0x0a;
 pub const INTCON_ADDR: u8 = 0x0b;
 
+// The program counter is 13 bits wide
+pub const PC_MASK: u16 = 0x1fff;
+
+// Bit 7 of a register file address selects bank 1
+pub const BANK1_ADDR: u8 = 0x80;
+// General purpose registers, mirrored in bank 1 at 0x8c-0xcf
+pub const GPR_START: u8 = 0x0c;
+pub const GPR_END: u8 = 0x4f;
+
+// Address a register is stored at, registers mirrored in bank 1 map to their bank 0 address
+pub fn canonical_address(address: u8) -> u8 {
+    match address & 0x7f {
+        PCL_ADDR | STATUS_ADDR | FSR_ADDR | PCLATH_ADDR | INTCON_ADDR | GPR_START..=GPR_END => address & 0x7f,
+        _ => address,
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ResetKind {
+    PowerOn,
+    // MCLR pulled low during normal operation
+    Mclr,
+    // MCLR pulled low while the cpu was sleeping
+    MclrSleep,
+    // Watchdog time-out during normal operation, a time-out during sleep is a wake-up
+    Watchdog,
+}
+
 pub struct SfrBank {
     pub w: u8,
-    pub indirect: u8,
     pub pcl: u8,
     pub status: u8,
     pub fsr: u8,
     pub pclath: u8,
     pub intcon: u8,
     pub tmr0: u8,
+    // Output latches of the ports, the pin levels are kept by the port model
     pub porta: u8,
     pub portb: u8,
     pub eedata: u8,
@@ -34,16 +69,15 @@ pub struct SfrBank {
     pub trisa: u8,
     pub trisb: u8,
     pub eecon1: u8,
-    pub eecon2: u8,
 }
 
 impl SfrBank {
+    // Power-on values, registers which are undefined after power-on start as 0
     pub fn new() -> Self {
         Self {
             w: 0,
-            indirect: 0,
             pcl: 0,
-            status: 0,
+            status: (1 << TO) | (1 << PD),
             fsr: 0,
             pclath: 0,
             intcon: 0,
@@ -52,46 +86,204 @@ impl SfrBank {
             portb: 0,
             eedata: 0,
             eeadr: 0,
-            option: 0,
-            trisa: 0,
-            trisb: 0,
+            option: 0xff,
+            trisa: 0x1f,
+            trisb: 0xff,
             eecon1: 0,
-            eecon2: 0,
         }
     }
 }
 
+impl Default for SfrBank {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Selects the register behind a register file address, once as shared and once as mutable reference
+macro_rules! select_register {
+    ($bus:ident, $address:expr, $($mutability:tt)*) => {{
+        let register = match ($address & BANK1_ADDR != 0, $address & 0x7f) {
+            // INDF itself is not a physical register, addressing it through FSR reads 0
+            (_, INDIRECT_ADDR) => return None,
+            (false, TMR0_ADDR) => &$($mutability)* $bus.sfr_bank.tmr0,
+            (true, OPTION_ADDR) => &$($mutability)* $bus.sfr_bank.option,
+            (_, PCL_ADDR) => &$($mutability)* $bus.sfr_bank.pcl,
+            (_, STATUS_ADDR) => &$($mutability)* $bus.sfr_bank.status,
+            (_, FSR_ADDR) => &$($mutability)* $bus.sfr_bank.fsr,
+            (false, PORTA_ADDR) => &$($mutability)* $bus.sfr_bank.porta,
+            (true, TRISA_ADDR) => &$($mutability)* $bus.sfr_bank.trisa,
+            (false, PORTB_ADDR) => &$($mutability)* $bus.sfr_bank.portb,
+            (true, TRISB_ADDR) => &$($mutability)* $bus.sfr_bank.trisb,
+            (false, EEDATA_ADDR) => &$($mutability)* $bus.sfr_bank.eedata,
+            (true, EECON1_ADDR) => &$($mutability)* $bus.sfr_bank.eecon1,
+            (false, EEADR_ADDR) => &$($mutability)* $bus.sfr_bank.eeadr,
+            (_, PCLATH_ADDR) => &$($mutability)* $bus.sfr_bank.pclath,
+            (_, INTCON_ADDR) => &$($mutability)* $bus.sfr_bank.intcon,
+            (_, offset @ GPR_START..=GPR_END) => &$($mutability)* $bus.memory[offset as usize],
+            // 0x07, EECON2 and everything above the general purpose registers
+            _ => return None,
+        };
+
+        Some(register)
+    }};
+}
+
 pub struct DataBus {
+    pub pc: u16,
+    // Set when the program wrote to PCL, which acts as a jump
+    pub pcl_written: bool,
+    // Indexed by the bank 0 address, only the general purpose registers are used
     pub memory: [u8; 0x80],
-    pub stack: Vec<u16>,
+    pub stack: Stack,
     pub sfr_bank: SfrBank,
+    pub prescaler: Prescaler,
+    pub timer0: Timer0,
+    pub port_a: Port,
+    pub port_b: PortB,
+    pub watchdog: Watchdog,
+    pub eeprom: Eeprom,
+    pub watchpoints: Watchpoints,
 }
 
 impl DataBus {
     pub fn new() -> Self {
-        Self {
+        let mut bus = Self {
+            pc: 0,
+            pcl_written: false,
             memory: [0; 0x80],
-            stack: Vec::new(),
+            stack: Stack::new(),
             sfr_bank: SfrBank::new(),
+            prescaler: Prescaler::new(),
+            timer0: Timer0::new(),
+            port_a: Port::new(PORTA_MASK, PORTA_OPEN_DRAIN),
+            port_b: PortB::new(),
+            watchdog: Watchdog::new(),
+            eeprom: Eeprom::new(),
+            watchpoints: Watchpoints::new(),
+        };
+
+        bus.reset(ResetKind::PowerOn);
+        bus
+    }
+
+    // Advances all peripherals by one instruction cycle
+    pub fn tick(&mut self) {
+        self.timer0.tick(&mut self.sfr_bank, &mut self.prescaler);
+    }
+
+    // The eeprom write timer keeps running during sleep
+    pub fn tick_eeprom(&mut self, cycle: Duration) {
+        self.eeprom.tick(cycle, &mut self.sfr_bank);
+    }
+
+    // Advances the watchdog by one instruction cycle and returns true on a time-out
+    pub fn tick_watchdog(&mut self, cycle: Duration) -> bool {
+        self.watchdog.tick(cycle, &self.sfr_bank, &mut self.prescaler)
+    }
+
+    // CLRWDT clears the watchdog and its prescaler and sets TO and PD
+    pub fn clear_watchdog(&mut self) {
+        self.watchdog.clear();
+
+        if Prescaler::assigned_to_wdt(self.sfr_bank.option) {
+            self.prescaler.clear();
+        }
+
+        set_bit(&mut self.sfr_bank.status, TO);
+        set_bit(&mut self.sfr_bank.status, PD);
+    }
+
+    // Sets the registers to their reset values (datasheet table 6-4). All resets
+    // except power-on keep W, the general purpose registers and the stack.
+    pub fn reset(&mut self, kind: ResetKind) {
+        let status = self.sfr_bank.status;
+
+        if kind == ResetKind::PowerOn {
+            self.memory = [0; 0x80];
+            self.stack = Stack::new();
+            self.sfr_bank = SfrBank::new();
+        } else {
+            // IRP, RP1 and RP0 are cleared, TO and PD tell the reset cause
+            self.sfr_bank.status &= (1 << TO) | (1 << PD) | (1 << Z) | (1 << DC) | (1 << C);
+            self.sfr_bank.pclath = 0;
+            self.sfr_bank.intcon &= 1 << RBIF;
+            // EECON1 becomes ---0 q000, only WRERR may stay set
+            self.sfr_bank.eecon1 &= 1 << WRERR;
+        }
+
+        // Every reset sets OPTION and the TRIS registers to all ones
+        self.sfr_bank.option = 0xff;
+        self.sfr_bank.trisa = 0x1f;
+        self.sfr_bank.trisb = 0xff;
+
+        match kind {
+            ResetKind::PowerOn => {}
+            ResetKind::Mclr => {
+                set_bit_enabled(&mut self.sfr_bank.status, TO, get_bit(status, TO));
+                set_bit_enabled(&mut self.sfr_bank.status, PD, get_bit(status, PD));
+            }
+            ResetKind::MclrSleep => {
+                set_bit(&mut self.sfr_bank.status, TO);
+                clear_bit(&mut self.sfr_bank.status, PD);
+            }
+            ResetKind::Watchdog => {
+                clear_bit(&mut self.sfr_bank.status, TO);
+                set_bit(&mut self.sfr_bank.status, PD);
+            }
+        }
+
+        self.set_pc(0);
+        self.pcl_written = false;
+        self.prescaler.clear();
+        self.timer0 = Timer0::new();
+        self.watchdog.clear();
+
+        // The eeprom keeps its content, a write interrupted by MCLR or WDT sets WRERR
+        if self.eeprom.reset() && kind != ResetKind::PowerOn {
+            set_bit(&mut self.sfr_bank.eecon1, WRERR);
         }
+
+        // The levels applied to the pins from outside are not affected by a reset
+        self.update_pins();
     }
 
-    pub fn load_pc(&mut self, mut value: u16) {
+    // Sets the level of an external porta pin
+    pub fn set_porta_pin(&mut self, bit: usize, level: bool) {
+        self.port_a.set_external(bit, level);
+        self.update_pins();
+    }
+
+    // Sets the level of an external portb pin
+    pub fn set_portb_pin(&mut self, bit: usize, level: bool) {
+        self.port_b.port.set_external(bit, level);
+        self.update_pins();
+    }
+
+    // Recalculates the pin levels after a latch, tris or external level changed
+    pub fn update_pins(&mut self) {
+        self.port_a.update(self.sfr_bank.porta, self.sfr_bank.trisa, false);
+        let t0cki = get_bit(self.port_a.pins(), RA4);
+        self.timer0.set_t0cki(t0cki, &mut self.sfr_bank, &mut self.prescaler);
+
+        self.port_b.update(&mut self.sfr_bank);
+    }
+
+    pub fn load_pc(&mut self, value: u16) {
         // When loading pc from GOTO or CALL instruction
-        // The upper two bits are being ignored
-        // -> only 11 bits from value are loaded
-        self.sfr_bank.pclath = (self.sfr_bank.pclath & 0b11000) | ((value >> 8) as u8 & 0b00111);
-        self.sfr_bank.pcl = value as u8;
+        // only 11 bits from value are loaded, the upper
+        // two bits are taken from PCLATH<4:3>
+        let page = ((self.sfr_bank.pclath & 0b11000) as u16) << 8;
+        self.set_pc(page | (value & 0b111_1111_1111));
     }
 
     pub fn get_pc(&self) -> u16 {
-        join_bytes(self.sfr_bank.pclath & 0b11111, self.sfr_bank.pcl)
+        self.pc
     }
 
     pub fn set_pc(&mut self, value: u16) {
-        // TODO: check if this is event needed
-        self.sfr_bank.pclath = ((value >> 8) & 0b11111) as u8;
-        self.sfr_bank.pcl = value as u8;
+        self.pc = value & PC_MASK;
+        self.sfr_bank.pcl = self.pc as u8;
     }
 
     pub fn inc_pc(&mut self, amount: u16) {
@@ -99,66 +291,172 @@ impl DataBus {
     }
 
     pub fn get_bit(&mut self, address: u8, bit: usize) -> bool {
-        get_bit(*self.map_address(address), bit)
+        get_bit(self.read_byte(address), bit)
     }
 
     pub fn clear_bit(&mut self, address: u8, bit: usize) {
-        clear_bit(self.map_address(address), bit);
+        let mut value = self.read_byte(address);
+        clear_bit(&mut value, bit);
+        self.write_byte(address, value);
     }
 
     pub fn set_bit(&mut self, address: u8, bit: usize) {
-        set_bit(self.map_address(address), bit);
+        let mut value = self.read_byte(address);
+        set_bit(&mut value, bit);
+        self.write_byte(address, value);
     }
 
+    // Resolves the file register operand of an instruction to a register file address.
+    // INDF addresses the register pointed to by FSR, otherwise RP0 selects the bank.
+    // The 16F84 has no banks 2 and 3, so IRP and RP1 do not take part in the address.
+    pub fn file_address(&self, file: u8) -> u8 {
+        let file = file & 0x7f;
+
+        if file == INDIRECT_ADDR {
+            self.sfr_bank.fsr
+        } else if get_bit(self.sfr_bank.status, RP0) {
+            BANK1_ADDR | file
+        } else {
+            file
+        }
+    }
+
+    // Reads a register file address (0x00-0xff), unimplemented locations read as 0
     pub fn read_byte(&mut self, address: u8) -> u8 {
-        let value = *self.map_address(address);
+        if address == PORTB_ADDR {
+            self.port_b.on_read();
+        }
+
+        let value = self.peek_byte(address);
         debug!("Reading {:02x} from {:02x}", value, address);
+        self.watchpoints.on_access(address, Access::Read, value);
         value
     }
 
+    // Reads a register file address without ending the portb mismatch condition
+    pub fn peek_byte(&self, address: u8) -> u8 {
+        // Reading a port returns the pin levels instead of the output latch
+        match address {
+            PORTA_ADDR => self.port_a.pins(),
+            PORTB_ADDR => self.port_b.port.pins(),
+            _ => self.register(address).map_or(0, |register| *register),
+        }
+    }
+
+    // Writes a register file address (0x00-0xff), writes to unimplemented locations are ignored
     pub fn write_byte(&mut self, address: u8, value: u8) {
-        let real_addr = self.map_address(address);
         debug!("Writing {:02x} to {:02x}", value, address);
-        *real_addr = value;
-    }
-
-    fn map_address(&mut self, address: u8) -> &mut u8 {
-        // Addresses over 127 are mapped to bank 1 (INDIRECT addressing)
-        let bank_selector = get_bit(self.sfr_bank.status, RP0) || (address > 127);
-        let transformed_address = address & 127;
-
-        if bank_selector {
-            // Bank 1 is used
-            match transformed_address {
-                INDIRECT_ADDR => &mut self.sfr_bank.indirect,
-                OPTION_ADDR => &mut self.sfr_bank.option,
-                PCL_ADDR => &mut self.sfr_bank.pcl,
-                STATUS_ADDR => &mut self.sfr_bank.status,
-                FSR_ADDR => &mut self.sfr_bank.fsr,
-                TRISA_ADDR => &mut self.sfr_bank.trisa,
-                TRISB_ADDR => &mut self.sfr_bank.trisb,
-                EECON1_ADDR => &mut self.sfr_bank.eecon1,
-                EECON2_ADDR => &mut self.sfr_bank.eecon2,
-                PCLATH_ADDR => &mut self.sfr_bank.pclath,
-                INTCON_ADDR => &mut self.sfr_bank.intcon,
-                _ => &mut self.memory[address as usize],
+        self.watchpoints.on_access(address, Access::Write, value);
+
+        match (address & BANK1_ADDR != 0, address & 0x7f) {
+            (true, EECON1_ADDR) => self.eeprom.write_eecon1(value, &mut self.sfr_bank),
+            // EECON2 is not a physical register
+            (true, EECON2_ADDR) => self.eeprom.write_eecon2(value),
+            // Writing PCL loads the whole pc with PCLATH<4:0>:value
+            (_, PCL_ADDR) => {
+                let high = ((self.sfr_bank.pclath & 0b11111) as u16) << 8;
+                self.set_pc(high | value as u16);
+                self.pcl_written = true;
             }
-        } else {
-            // Bank 0 is used
-            match transformed_address {
-                INDIRECT_ADDR => &mut self.sfr_bank.indirect,
-                TMR0_ADDR => &mut self.sfr_bank.tmr0,
-                PCL_ADDR => &mut self.sfr_bank.pcl,
-                STATUS_ADDR => &mut self.sfr_bank.status,
-                FSR_ADDR => &mut self.sfr_bank.fsr,
-                PORTA_ADDR => &mut self.sfr_bank.porta,
-                PORTB_ADDR => &mut self.sfr_bank.portb,
-                EEDATA_ADDR => &mut self.sfr_bank.eedata,
-                EEADR_ADDR => &mut self.sfr_bank.eeadr,
-                PCLATH_ADDR => &mut self.sfr_bank.pclath,
-                INTCON_ADDR => &mut self.sfr_bank.intcon,
-                _ => &mut self.memory[address as usize],
+            (false, TMR0_ADDR) => {
+                self.sfr_bank.tmr0 = value;
+                self.timer0.on_write(&self.sfr_bank, &mut self.prescaler);
             }
+            // TO and PD can only be changed by the hardware
+            (_, STATUS_ADDR) => {
+                let read_only = (1 << TO) | (1 << PD);
+                self.sfr_bank.status = (value & !read_only) | (self.sfr_bank.status & read_only);
+            }
+            _ => {
+                if let Some(register) = self.map_address(address) {
+                    *register = value;
+                }
+            }
+        }
+
+        self.update_pins();
+    }
+
+    fn register(&self, address: u8) -> Option<&u8> {
+        select_register!(self, address,)
+    }
+
+    fn map_address(&mut self, address: u8) -> Option<&mut u8> {
+        select_register!(self, address, mut)
+    }
+}
+
+impl Default for DataBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resets_report_their_cause_in_to_and_pd() {
+        let mut bus = DataBus::new();
+        assert_eq!(bus.sfr_bank.status & ((1 << TO) | (1 << PD)), (1 << TO) | (1 << PD));
+
+        bus.reset(ResetKind::Watchdog);
+        assert!(!get_bit(bus.sfr_bank.status, TO));
+        assert!(get_bit(bus.sfr_bank.status, PD));
+
+        // MCLR during normal operation leaves TO and PD unchanged
+        bus.reset(ResetKind::Mclr);
+        assert!(!get_bit(bus.sfr_bank.status, TO));
+        assert!(get_bit(bus.sfr_bank.status, PD));
+
+        bus.reset(ResetKind::MclrSleep);
+        assert!(get_bit(bus.sfr_bank.status, TO));
+        assert!(!get_bit(bus.sfr_bank.status, PD));
+    }
+
+    #[test]
+    fn resets_other_than_power_on_keep_w_and_the_gprs() {
+        let mut bus = DataBus::new();
+        bus.sfr_bank.w = 0x42;
+        bus.write_byte(0x0c, 0x12);
+        bus.write_byte(0x4f, 0x34);
+
+        for kind in [ResetKind::Mclr, ResetKind::MclrSleep, ResetKind::Watchdog] {
+            bus.reset(kind);
+            assert_eq!(bus.sfr_bank.w, 0x42);
+            assert_eq!(bus.peek_byte(0x0c), 0x12);
+            assert_eq!(bus.peek_byte(0x4f), 0x34);
+        }
+
+        bus.reset(ResetKind::PowerOn);
+        assert_eq!(bus.sfr_bank.w, 0);
+        assert_eq!(bus.peek_byte(0x0c), 0);
+    }
+
+    #[test]
+    fn resets_set_option_and_tris_to_all_ones() {
+        for kind in [ResetKind::PowerOn, ResetKind::Mclr, ResetKind::MclrSleep, ResetKind::Watchdog] {
+            let mut bus = DataBus::new();
+            bus.write_byte(0x81, 0x00);
+            bus.write_byte(0x85, 0x00);
+            bus.write_byte(0x86, 0x00);
+
+            bus.reset(kind);
+            assert_eq!(bus.peek_byte(0x81), 0xff);
+            assert_eq!(bus.peek_byte(0x85), 0x1f);
+            assert_eq!(bus.peek_byte(0x86), 0xff);
+        }
+    }
+
+    #[test]
+    fn resets_clear_eeif() {
+        for kind in [ResetKind::Mclr, ResetKind::MclrSleep, ResetKind::Watchdog] {
+            let mut bus = DataBus::new();
+            set_bit(&mut bus.sfr_bank.eecon1, EEIF);
+
+            bus.reset(kind);
+            assert_eq!(bus.sfr_bank.eecon1, 0);
         }
     }
 }
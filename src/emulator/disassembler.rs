@@ -0,0 +1,247 @@
+use super::instruction::*;
+use super::program_info::ProgramInfo;
+use super::rom_bus::RomBus;
+use std::fmt;
+
+// Special function registers of bank 0 and bank 1, files from 0x0c on are general purpose
+const BANK0_NAMES: [&str; 12] = [
+    "INDF", "TMR0", "PCL", "STATUS", "FSR", "PORTA", "PORTB", "", "EEDATA", "EEADR", "PCLATH", "INTCON",
+];
+const BANK1_NAMES: [&str; 12] = [
+    "INDF", "OPTION_REG", "PCL", "STATUS", "FSR", "TRISA", "TRISB", "", "EECON1", "EECON2", "PCLATH", "INTCON",
+];
+
+const STATUS_BITS: [&str; 8] = ["C", "DC", "Z", "NOT_PD", "NOT_TO", "RP0", "RP1", "IRP"];
+const INTCON_BITS: [&str; 8] = ["RBIF", "INTF", "T0IF", "RBIE", "INTE", "T0IE", "EEIE", "GIE"];
+const OPTION_BITS: [&str; 8] = ["PS0", "PS1", "PS2", "PSA", "T0SE", "T0CS", "INTEDG", "NOT_RBPU"];
+const EECON1_BITS: [&str; 8] = ["RD", "WR", "WREN", "WRERR", "EEIF", "", "", ""];
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DisassembledLine {
+    pub address: u16,
+    pub opcode: u16,
+    pub label: Option<String>,
+    pub text: String,
+}
+
+impl fmt::Display for DisassembledLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let label = self.label.as_deref().unwrap_or("");
+        write!(f, "{:04X} {:04X}  {:<10} {}", self.address, self.opcode, label, self.text)
+    }
+}
+
+// Turns instructions back into MPASM syntax, operands are named after the
+// special function registers and the labels and symbols of the program
+pub struct Disassembler<'a> {
+    info: &'a ProgramInfo,
+    // Names files below 0x0c after bank 1, e.g. TRISB instead of PORTB
+    pub bank1: bool,
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new(info: &'a ProgramInfo) -> Self {
+        Self { info, bank1: false }
+    }
+
+    pub fn instruction(&self, instruction: Instruction) -> String {
+        use Instruction::*;
+
+        match instruction {
+            AddWf(f, d) => self.byte_oriented("addwf", f, d),
+            AndWf(f, d) => self.byte_oriented("andwf", f, d),
+            ClrF(f) => format!("clrf {}", self.file(f)),
+            ClrW => String::from("clrw"),
+            ComF(f, d) => self.byte_oriented("comf", f, d),
+            DecF(f, d) => self.byte_oriented("decf", f, d),
+            DecFsz(f, d) => self.byte_oriented("decfsz", f, d),
+            IncF(f, d) => self.byte_oriented("incf", f, d),
+            IncFsz(f, d) => self.byte_oriented("incfsz", f, d),
+            IorWf(f, d) => self.byte_oriented("iorwf", f, d),
+            MovF(f, d) => self.byte_oriented("movf", f, d),
+            MovWf(f) => format!("movwf {}", self.file(f)),
+            Nop => String::from("nop"),
+            RlF(f, d) => self.byte_oriented("rlf", f, d),
+            RrF(f, d) => self.byte_oriented("rrf", f, d),
+            SubWf(f, d) => self.byte_oriented("subwf", f, d),
+            SwapWf(f, d) => self.byte_oriented("swapf", f, d),
+            XorWf(f, d) => self.byte_oriented("xorwf", f, d),
+            BcF(f, b) => self.bit_oriented("bcf", f, b),
+            BsF(f, b) => self.bit_oriented("bsf", f, b),
+            BtFsc(f, b) => self.bit_oriented("btfsc", f, b),
+            BtFss(f, b) => self.bit_oriented("btfss", f, b),
+            AddLw(k) => format!("addlw {}", hex_literal(k.0 as u16)),
+            AndLw(k) => format!("andlw {}", hex_literal(k.0 as u16)),
+            Call(a) => format!("call {}", self.address(a)),
+            ClearWdt => String::from("clrwdt"),
+            Goto(a) => format!("goto {}", self.address(a)),
+            IorLw(k) => format!("iorlw {}", hex_literal(k.0 as u16)),
+            MovLw(k) => format!("movlw {}", hex_literal(k.0 as u16)),
+            RetFie => String::from("retfie"),
+            RetLw(k) => format!("retlw {}", hex_literal(k.0 as u16)),
+            Return => String::from("return"),
+            Sleep => String::from("sleep"),
+            SubLw(k) => format!("sublw {}", hex_literal(k.0 as u16)),
+            XorLw(k) => format!("xorlw {}", hex_literal(k.0 as u16)),
+        }
+    }
+
+    // Words which are no valid instruction are shown as data
+    pub fn opcode(&self, opcode: u16) -> String {
+        match Instruction::from(opcode) {
+            Ok(instruction) => self.instruction(instruction),
+            Err(_) => format!("dw {}", hex_literal(opcode)),
+        }
+    }
+
+    // Disassembles the whole program memory that was loaded
+    pub fn rom(&self, rom: &RomBus) -> Vec<DisassembledLine> {
+        let (start, end) = rom.get_rom_boundary();
+
+        (start..=end)
+            .map(|address| {
+                let opcode = rom.read_opcode(address);
+                DisassembledLine {
+                    address,
+                    opcode,
+                    label: self.info.label_at(address).map(String::from),
+                    text: self.opcode(opcode),
+                }
+            })
+            .collect()
+    }
+
+    fn byte_oriented(&self, mnemonic: &str, file: FileRegister, destination: DestinationFlag) -> String {
+        let destination = if destination.0 { "f" } else { "w" };
+        format!("{} {},{}", mnemonic, self.file(file), destination)
+    }
+
+    fn bit_oriented(&self, mnemonic: &str, file: FileRegister, bit: BitIndex) -> String {
+        let names = match (self.bank1, file.0) {
+            (_, 0x03) => STATUS_BITS,
+            (_, 0x0b) => INTCON_BITS,
+            (true, 0x01) => OPTION_BITS,
+            (true, 0x08) => EECON1_BITS,
+            _ => [""; 8],
+        };
+
+        match names[bit.0] {
+            "" => format!("{} {},{}", mnemonic, self.file(file), bit.0),
+            name => format!("{} {},{}", mnemonic, self.file(file), name),
+        }
+    }
+
+    fn file(&self, file: FileRegister) -> String {
+        let names = if self.bank1 { BANK1_NAMES } else { BANK0_NAMES };
+
+        match names.get(file.0 as usize) {
+            Some(name) if !name.is_empty() => String::from(*name),
+            _ => self.info.symbol_for(file.0 as u16).map_or_else(|| hex_literal(file.0 as u16), String::from),
+        }
+    }
+
+    fn address(&self, address: Address) -> String {
+        self.info.label_at(address.0).map_or_else(|| hex_literal(address.0), String::from)
+    }
+}
+
+// Names every call and goto target without a label after its address, e.g. l_0010,
+// which gives programs loaded from hex files readable jumps
+pub fn label_jump_targets(rom: &RomBus, info: &mut ProgramInfo) {
+    let (start, end) = rom.get_rom_boundary();
+
+    for address in start..=end {
+        if let Ok(Instruction::Call(target) | Instruction::Goto(target)) = Instruction::from(rom.read_opcode(address)) {
+            if info.label_at(target.0).is_none() {
+                info.add_label(&format!("l_{:04x}", target.0), target.0);
+            }
+        }
+    }
+}
+
+// Register file address of a special function register, e.g. 0x86 for TRISB
+pub fn sfr_address(name: &str) -> Option<u8> {
+    let find = |names: &[&str]| names.iter().position(|sfr| !sfr.is_empty() && sfr.eq_ignore_ascii_case(name));
+
+    find(&BANK0_NAMES).map(|index| index as u8).or_else(|| find(&BANK1_NAMES).map(|index| 0x80 | index as u8))
+}
+
+// Register file address and bit of a flag, e.g. (0x03, 2) for Z
+pub fn flag_bit(name: &str) -> Option<(u8, usize)> {
+    [(0x03, STATUS_BITS), (0x0b, INTCON_BITS), (0x81, OPTION_BITS), (0x88, EECON1_BITS)]
+        .iter()
+        .find_map(|(address, bits)| {
+            let bit = bits.iter().position(|bit| !bit.is_empty() && bit.eq_ignore_ascii_case(name))?;
+            Some((*address, bit))
+        })
+}
+
+// Hex number in listing notation, a leading digit keeps it apart from names (0ch instead of ch)
+fn hex_literal(value: u16) -> String {
+    let digits = format!("{:02x}", value);
+
+    if digits.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        format!("0{}h", digits)
+    } else {
+        format!("{}h", digits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{parse_lst_file, Cpu};
+    use std::fs;
+
+    // One opcode of every instruction, files 0x20 are named after the symbol f
+    const OPCODES: [(u16, &str); 35] = [
+        (0x0720, "addwf f,w"), (0x05a0, "andwf f,f"), (0x01a0, "clrf f"), (0x0100, "clrw"),
+        (0x0920, "comf f,w"), (0x03a0, "decf f,f"), (0x0ba0, "decfsz f,f"), (0x0aa0, "incf f,f"),
+        (0x0fa0, "incfsz f,f"), (0x0420, "iorwf f,w"), (0x0820, "movf f,w"), (0x00a0, "movwf f"),
+        (0x0000, "nop"), (0x0da0, "rlf f,f"), (0x0c20, "rrf f,w"), (0x0220, "subwf f,w"),
+        (0x0ea0, "swapf f,f"), (0x0620, "xorwf f,w"), (0x13a0, "bcf f,7"), (0x1420, "bsf f,0"),
+        (0x19a0, "btfsc f,3"), (0x1da0, "btfss f,3"), (0x3e01, "addlw 01h"), (0x390f, "andlw 0fh"),
+        (0x2123, "call 123h"), (0x0064, "clrwdt"), (0x2845, "goto 45h"), (0x3800, "iorlw 00h"),
+        (0x30ab, "movlw 0abh"), (0x0009, "retfie"), (0x3442, "retlw 42h"), (0x0008, "return"),
+        (0x0063, "sleep"), (0x3c10, "sublw 10h"), (0x3aff, "xorlw 0ffh"),
+    ];
+
+    #[test]
+    fn disassembly_matches_the_mnemonics() {
+        let mut info = ProgramInfo::new();
+        info.add_symbol("f", 0x20);
+        let mut disassembler = Disassembler::new(&info);
+
+        for (opcode, text) in OPCODES.iter() {
+            assert_eq!(disassembler.opcode(*opcode), *text, "{:04x}", opcode);
+        }
+
+        assert_eq!(disassembler.opcode(0x1683), "bsf STATUS,RP0");
+        assert_eq!(disassembler.opcode(0x0086), "movwf PORTB");
+        assert_eq!(disassembler.opcode(0x3fff + 0x4000), "dw 7fffh");
+
+        disassembler.bank1 = true;
+        assert_eq!(disassembler.opcode(0x0086), "movwf TRISB");
+        assert_eq!(disassembler.opcode(0x1381), "bcf OPTION_REG,NOT_RBPU");
+    }
+
+    #[test]
+    fn disassembly_uses_program_names() {
+        let listing = fs::read_to_string("programs/TPicSim4.LST").expect("Failed to read listing");
+        let mut cpu = Cpu::new();
+        cpu.load(parse_lst_file(&listing).expect("Failed to parse listing"));
+        let lines = Disassembler::new(cpu.program_info()).rom(&cpu.rom_bus);
+
+        assert_eq!(lines[0x01].text, "movwf wert1");
+        assert_eq!(lines[0x06].text, "rlf wert1,w");
+        assert_eq!(lines[0x10].to_string(), "0010 3E01  loop1      addlw 01h");
+        assert_eq!(lines[0x1b].text, "goto loop2");
+
+        // Without a listing jump targets are named after their address
+        let mut info = ProgramInfo::new();
+        label_jump_targets(&cpu.rom_bus, &mut info);
+        let lines = Disassembler::new(&info).rom(&cpu.rom_bus);
+        assert_eq!(lines[0x1b].text, "goto l_0018");
+        assert_eq!(lines[0x18].label.as_deref(), Some("l_0018"));
+    }
+}
@@ -0,0 +1,236 @@
+use std::time::Duration;
+
+use super::bits::*;
+use super::data_bus::SfrBank;
+use super::hex::*;
+
+pub const EEPROM_SIZE: usize = 64;
+// Word address of the eeprom data in hex files (byte address 0x4200 in INHX8M)
+pub const EEPROM_HEX_ADDRESS: u32 = 0x2100;
+pub const EEPROM_WRITE_TIME: Duration = Duration::from_millis(10);
+
+// Only the lower five bits of eecon1 are implemented
+const EECON1_MASK: u8 = 0x1f;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum WriteSequence {
+    Idle,
+    Received55,
+    Unlocked,
+}
+
+struct PendingWrite {
+    address: usize,
+    value: u8,
+    remaining: Duration,
+}
+
+pub struct Eeprom {
+    pub data: [u8; EEPROM_SIZE],
+    pub write_time: Duration,
+    sequence: WriteSequence,
+    pending: Option<PendingWrite>,
+}
+
+impl Eeprom {
+    pub fn new() -> Self {
+        Self {
+            data: [0; EEPROM_SIZE],
+            write_time: EEPROM_WRITE_TIME,
+            sequence: WriteSequence::Idle,
+            pending: None,
+        }
+    }
+
+    pub fn is_writing(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    // Writing 0x55 followed by 0xaa to eecon2 unlocks the WR bit
+    pub fn write_eecon2(&mut self, value: u8) {
+        self.sequence = match (self.sequence, value) {
+            (_, 0x55) => WriteSequence::Received55,
+            (WriteSequence::Received55, 0xaa) => WriteSequence::Unlocked,
+            _ => WriteSequence::Idle,
+        };
+    }
+
+    pub fn write_eecon1(&mut self, value: u8, sfr_bank: &mut SfrBank) {
+        let old = sfr_bank.eecon1;
+        let mut new = value & EECON1_MASK;
+
+        // RD and WR can only be set by software, the hardware clears them
+        if get_bit(old, RD) {
+            set_bit(&mut new, RD);
+        }
+        if get_bit(old, WR) {
+            set_bit(&mut new, WR);
+        }
+
+        if get_bit(new, RD) {
+            sfr_bank.eedata = self.data[sfr_bank.eeadr as usize % EEPROM_SIZE];
+            clear_bit(&mut new, RD);
+        }
+
+        if get_bit(new, WR) && !get_bit(old, WR) {
+            if get_bit(new, WREN) && self.sequence == WriteSequence::Unlocked {
+                debug!("Starting eeprom write of {:02x} to {:02x}", sfr_bank.eedata, sfr_bank.eeadr);
+
+                self.pending = Some(PendingWrite {
+                    address: sfr_bank.eeadr as usize % EEPROM_SIZE,
+                    value: sfr_bank.eedata,
+                    remaining: self.write_time,
+                });
+            } else {
+                clear_bit(&mut new, WR);
+            }
+        }
+
+        self.sequence = WriteSequence::Idle;
+        sfr_bank.eecon1 = new;
+    }
+
+    // Advances a running write by one instruction cycle
+    pub fn tick(&mut self, cycle: Duration, sfr_bank: &mut SfrBank) {
+        let finished = match &mut self.pending {
+            Some(write) => {
+                write.remaining = write.remaining.saturating_sub(cycle);
+                write.remaining == Duration::from_secs(0)
+            }
+            None => false,
+        };
+
+        if finished {
+            let write = self.pending.take().unwrap();
+            self.data[write.address] = write.value;
+
+            clear_bit(&mut sfr_bank.eecon1, WR);
+            set_bit(&mut sfr_bank.eecon1, EEIF);
+        }
+    }
+
+    // Loads the eeprom content from a raw binary or Intel HEX image
+    pub fn load_image(&mut self, content: &[u8]) -> Result<(), String> {
+        if is_hex_file(content) {
+            let text = String::from_utf8_lossy(content);
+            self.load_hex_records(&parse_hex_file(&text)?);
+            Ok(())
+        } else if content.len() > EEPROM_SIZE {
+            Err(format!(
+                "Eeprom image is {} bytes, only {} bytes fit into the eeprom",
+                content.len(),
+                EEPROM_SIZE
+            ))
+        } else {
+            self.data[..content.len()].copy_from_slice(content);
+            Ok(())
+        }
+    }
+
+    // Loads the eeprom data region (0x2100) from hex records, other regions are ignored
+    pub fn load_hex_records(&mut self, records: &[HexRecord]) {
+        let start = EEPROM_HEX_ADDRESS * 2;
+        let end = start + EEPROM_SIZE as u32 * 2;
+
+        for record in records {
+            for (address, value) in (record.address..).zip(&record.data) {
+                // Every eeprom byte is stored as the low byte of a program word
+                if address < start || address >= end || address % 2 != 0 {
+                    continue;
+                }
+
+                self.data[((address - start) / 2) as usize] = *value;
+            }
+        }
+    }
+
+    pub fn to_binary(&self) -> Vec<u8> {
+        self.data.to_vec()
+    }
+
+    pub fn to_hex(&self) -> String {
+        let data = self.data.iter().flat_map(|value| vec![*value, 0]).collect();
+        write_hex_file(&[HexRecord {
+            address: EEPROM_HEX_ADDRESS * 2,
+            data,
+        }])
+    }
+
+    // Aborts a running write, returns true if a write was interrupted
+    pub fn reset(&mut self) -> bool {
+        self.sequence = WriteSequence::Idle;
+        self.pending.take().is_some()
+    }
+}
+
+impl Default for Eeprom {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Cpu;
+    use std::fs;
+
+    fn test_image() -> Vec<u8> {
+        (0..EEPROM_SIZE as u8).map(|i| i.wrapping_mul(37)).collect()
+    }
+
+    #[test]
+    fn binary_images_round_trip() {
+        let mut eeprom = Eeprom::new();
+        eeprom.load_image(&test_image()).unwrap();
+        assert_eq!(eeprom.to_binary(), test_image());
+
+        // Shorter images only replace the first bytes
+        eeprom.load_image(&[0xaa, 0xbb]).unwrap();
+        assert_eq!(&eeprom.data[..3], &[0xaa, 0xbb, test_image()[2]]);
+    }
+
+    #[test]
+    fn hex_images_round_trip() {
+        let mut eeprom = Eeprom::new();
+        eeprom.data.copy_from_slice(&test_image());
+
+        let hex = eeprom.to_hex();
+        let mut loaded = Eeprom::new();
+        loaded.load_image(hex.as_bytes()).unwrap();
+        assert_eq!(loaded.data, eeprom.data);
+
+        let mut from_records = Eeprom::new();
+        from_records.load_hex_records(&parse_hex_file(&hex).unwrap());
+        assert_eq!(from_records.data, eeprom.data);
+    }
+
+    #[test]
+    fn binary_images_larger_than_the_eeprom_are_rejected() {
+        let mut eeprom = Eeprom::new();
+        assert!(eeprom.load_image(&[0x12; EEPROM_SIZE + 1]).is_err());
+        assert_eq!(eeprom.data, [0; EEPROM_SIZE]);
+    }
+
+    #[test]
+    fn save_eeprom_picks_the_format_by_extension() {
+        let mut cpu = Cpu::new();
+        let dir = std::env::temp_dir();
+        let binary_path = dir.join(format!("rssim-eeprom-{}.bin", std::process::id()));
+        let hex_path = dir.join(format!("rssim-eeprom-{}.HEX", std::process::id()));
+
+        fs::write(&binary_path, test_image()).unwrap();
+        cpu.load_eeprom(&binary_path).unwrap();
+        cpu.save_eeprom(&binary_path).unwrap();
+        cpu.save_eeprom(&hex_path).unwrap();
+
+        let binary = fs::read(&binary_path).unwrap();
+        let hex = fs::read_to_string(&hex_path).unwrap();
+        fs::remove_file(&binary_path).unwrap();
+        fs::remove_file(&hex_path).unwrap();
+
+        assert_eq!(binary, test_image());
+        assert!(hex.starts_with(':'));
+        assert_eq!(hex, cpu.eeprom().to_hex());
+    }
+}
@@ -0,0 +1,51 @@
+use super::breakpoint::BreakReason;
+use super::data_bus::ResetKind;
+use super::stack::StackError;
+use std::time::Duration;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PortId {
+    A,
+    B,
+}
+
+// Everything a frontend may want to display, emitted by the cpu while it runs
+#[derive(Debug, Clone, PartialEq)]
+pub enum SimEvent {
+    // A file register was written, address is a register file address (0x00-0xff)
+    RegisterChanged { address: u8, value: u8 },
+    WChanged(u8),
+    StatusChanged(u8),
+    IntconChanged(u8),
+    OptionChanged(u8),
+    FsrChanged(u8),
+    Timer0Changed(u8),
+    PcChanged { pc: u16, pcl: u8, pclath: u8 },
+    // Listing line of the instruction the pc left or arrived at
+    LineLeft(usize),
+    LineEntered(usize),
+    // Return addresses from the oldest to the most recent one
+    StackChanged(Vec<u16>),
+    StackError { error: StackError, address: u16 },
+    PinChanged { port: PortId, bit: usize, level: bool },
+    DirectionChanged { port: PortId, bit: usize, input: bool },
+    PrescalerChanged { ratio: u16 },
+    // Time left until the watchdog times out, None while it is disabled
+    WatchdogChanged { remaining: Option<Duration> },
+    InterruptEntered { return_address: u16 },
+    Halted,
+    WokeUp,
+    Reset(ResetKind),
+    // A breakpoint or watchpoint stopped the cpu after the last instruction
+    BreakpointHit(BreakReason),
+}
+
+pub trait Observer {
+    fn on_event(&mut self, event: &SimEvent);
+}
+
+impl<F: FnMut(&SimEvent)> Observer for F {
+    fn on_event(&mut self, event: &SimEvent) {
+        self(event)
+    }
+}
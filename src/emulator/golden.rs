@@ -0,0 +1,473 @@
+// Runs the TPicSim reference programs and checks them against the values documented in their listings
+use std::collections::HashMap;
+use std::fs;
+
+use super::bits::*;
+use super::cpu::Cpu;
+use super::parser::parse_lst_file;
+
+// Expected values after one execution of an instruction, e.g. [("W", 0x10), ("Z", 0)]
+type Expectation = Vec<(String, u8)>;
+
+struct GoldenRun {
+    name: String,
+    cpu: Cpu,
+    symbols: HashMap<String, u8>,
+    // Expected values per address, one entry for each execution of the instruction
+    expectations: HashMap<u16, Vec<Expectation>>,
+    executions: HashMap<u16, usize>,
+    checks: usize,
+    failures: Vec<String>,
+}
+
+impl GoldenRun {
+    fn load(name: &str) -> Self {
+        let listing = fs::read_to_string(format!("programs/{}.LST", name)).expect("Failed to read listing");
+
+        // Like on the gui board every pin is driven, so none of them is floating
+        let mut cpu = Cpu::new();
+        cpu.data_bus.port_a.drive_all();
+        cpu.data_bus.port_b.port.drive_all();
+        cpu.load(parse_lst_file(&listing).expect("Failed to parse listing"));
+
+        Self {
+            name: String::from(name),
+            cpu,
+            symbols: parse_symbols(&listing),
+            expectations: parse_expectations(&listing),
+            executions: HashMap::new(),
+            checks: 0,
+            failures: Vec::new(),
+        }
+    }
+
+    fn step(&mut self) {
+        let pc = self.cpu.data_bus.get_pc();
+        let executed = !self.cpu.sleeping;
+        self.cpu.step();
+
+        if !executed {
+            return;
+        }
+
+        let execution = self.executions.entry(pc).or_insert(0);
+        let expectation = self.expectations.get(&pc).and_then(|e| e.get(*execution)).cloned();
+        *execution += 1;
+
+        for (key, expected) in expectation.unwrap_or_default() {
+            if let Some(actual) = self.value(&key) {
+                self.checks += 1;
+
+                if actual != expected {
+                    self.failures.push(format!(
+                        "{} {:04x} (execution {}): {} expected {:02x}, got {:02x}",
+                        self.name, pc, self.executions[&pc], key, expected, actual
+                    ));
+                }
+            }
+        }
+    }
+
+    fn step_n(&mut self, steps: usize) {
+        for _ in 0..steps {
+            self.step();
+        }
+    }
+
+    fn run_to(&mut self, pc: u16, max_steps: usize) {
+        for _ in 0..max_steps {
+            if self.cpu.data_bus.get_pc() == pc && !self.cpu.sleeping {
+                return;
+            }
+            self.step();
+        }
+
+        panic!("{} did not reach {:04x}, pc is {:04x}", self.name, pc, self.cpu.data_bus.get_pc());
+    }
+
+    fn register(&self, address: u8) -> u8 {
+        self.cpu.data_bus.memory[address as usize]
+    }
+
+    // Resolves a name used in the listing comments to its current value
+    fn value(&self, key: &str) -> Option<u8> {
+        let status = self.cpu.data_bus.sfr_bank.status;
+
+        match key {
+            "W" => Some(self.cpu.data_bus.sfr_bank.w),
+            "C" => Some(get_bit(status, C) as u8),
+            "DC" => Some(get_bit(status, DC) as u8),
+            "Z" => Some(get_bit(status, Z) as u8),
+            "TO" => Some(get_bit(status, TO) as u8),
+            "PD" => Some(get_bit(status, PD) as u8),
+            _ => {
+                // Either a register like F10 or a symbol defined with equ
+                let address = key
+                    .strip_prefix('F')
+                    .filter(|a| a.len() == 2)
+                    .and_then(parse_number)
+                    .or_else(|| self.symbols.get(&key.to_lowercase()).copied())?;
+
+                Some(self.cpu.register(address))
+            }
+        }
+    }
+
+    fn finish(self) {
+        assert!(self.failures.is_empty(), "\n{}", self.failures.join("\n"));
+    }
+}
+
+// Splits a listing line into its address (if it holds an opcode) and the source text
+fn split_line(line: &str) -> (Option<u16>, &str) {
+    let address = line.get(0..4).and_then(|a| u16::from_str_radix(a, 16).ok());
+    (address, line.get(25..).unwrap_or("").trim())
+}
+
+// Parses numbers like 0ch, 20h or 36, which are hex in the listing comments
+fn parse_number(text: &str) -> Option<u8> {
+    let digits = text.trim_end_matches(['h', 'H']);
+    u8::from_str_radix(digits, 16).ok()
+}
+
+fn parse_symbols(listing: &str) -> HashMap<String, u8> {
+    let mut symbols = HashMap::new();
+
+    for line in listing.lines() {
+        let tokens: Vec<&str> = split_line(line).1.split_whitespace().collect();
+
+        if let [name, "equ", value, ..] = tokens[..] {
+            if let Some(value) = parse_number(value) {
+                symbols.insert(name.to_lowercase(), value);
+            }
+        }
+    }
+
+    symbols
+}
+
+// Collects key=value pairs from a comment, values like x or ?? are skipped
+fn parse_expectation(comment: &str) -> Expectation {
+    let mut expectation: Expectation = Vec::new();
+    let mut sequences = Vec::new();
+    let mut last_key = None;
+
+    for part in comment.split([',', ';']) {
+        let sides: Vec<&str> = part.split('=').collect();
+
+        // A value without a key continues a sequence like W=20h, 21h, 22h, which
+        // describes several executions, so the key can't be checked
+        if sides.len() == 1 {
+            let value = sides[0].split_whitespace().next().unwrap_or("");
+            // Bare words like C or DC are flag names, not hex values
+            let is_value = value.ends_with(['h', 'H']) || value.starts_with(|c: char| c.is_ascii_digit());
+
+            if let (Some(key), true) = (last_key, is_value && parse_number(value).is_some()) {
+                sequences.push(key);
+            }
+            continue;
+        }
+
+        last_key = None;
+        if sides.len() != 2 {
+            continue;
+        }
+
+        let key = sides[0].split_whitespace().last();
+        let value = sides[1].split_whitespace().next().and_then(parse_number);
+
+        if let (Some(key), Some(value)) = (key, value) {
+            last_key = Some(key);
+
+            // Repeated keys list the values of later executions
+            if !expectation.iter().any(|(k, _)| k == key) {
+                expectation.push((String::from(key), value));
+            }
+        }
+    }
+
+    expectation.retain(|(key, _)| !sequences.contains(&key.as_str()));
+    expectation
+}
+
+// Comment lines following an instruction describe its following executions
+fn parse_expectations(listing: &str) -> HashMap<u16, Vec<Expectation>> {
+    let mut expectations: HashMap<u16, Vec<Expectation>> = HashMap::new();
+    let mut current = None;
+
+    for line in listing.lines() {
+        let (address, source) = split_line(line);
+        let comment = source.find(';').map(|i| &source[i + 1..]);
+
+        match (address, comment) {
+            (Some(address), comment) => {
+                let expectation = comment.map(parse_expectation).unwrap_or_default();
+                expectations.entry(address).or_default().push(expectation);
+                current = Some(address);
+            }
+            (None, Some(comment)) if source.starts_with(';') => {
+                let expectation = parse_expectation(comment);
+                if let (Some(address), false) = (current, expectation.is_empty()) {
+                    expectations.entry(address).or_default().push(expectation);
+                }
+            }
+            // Labels and directives end the comments of the previous instruction
+            (None, _) if !source.is_empty() => current = None,
+            _ => {}
+        }
+    }
+
+    expectations
+}
+
+#[test]
+fn tpicsim1_literal_instructions() {
+    let mut run = GoldenRun::load("TPicSim1");
+    run.run_to(0x0006, 100);
+
+    assert!(run.checks > 0);
+    run.finish();
+}
+
+#[test]
+fn tpicsim2_call_and_return() {
+    let mut run = GoldenRun::load("TPicSim2");
+    run.run_to(0x0005, 100);
+
+    assert_eq!(run.cpu.data_bus.sfr_bank.w, 0x77);
+    run.finish();
+}
+
+#[test]
+fn tpicsim3_byte_instructions() {
+    let mut run = GoldenRun::load("TPicSim3");
+    run.run_to(0x0015, 100);
+
+    assert!(run.checks > 0);
+    run.finish();
+}
+
+#[test]
+fn tpicsim4_rotate_and_loops() {
+    let mut run = GoldenRun::load("TPicSim4");
+    run.run_to(0x001c, 1000);
+
+    assert_eq!(run.register(0x0c), 0x00);
+    assert_eq!(run.register(0x0d), 0x10);
+    run.finish();
+}
+
+#[test]
+fn tpicsim5_bit_instructions() {
+    let mut run = GoldenRun::load("TPicSim5");
+    run.run_to(0x0013, 100);
+
+    // In wert2 muss 04h stehen
+    assert_eq!(run.register(0x0d), 0x04);
+    run.finish();
+}
+
+#[test]
+fn tpicsim6_indirect_addressing() {
+    let mut run = GoldenRun::load("TPicSim6");
+    run.run_to(0x0014, 1000);
+
+    // Im W-Register muss 78h stehen
+    assert_eq!(run.cpu.data_bus.sfr_bank.w, 0x78);
+    run.run_to(0x0027, 100);
+    run.finish();
+}
+
+#[test]
+fn tpicsim7_timer0() {
+    let mut run = GoldenRun::load("TPicSim7");
+
+    // Instruction clock with prescaler 1:4 and 1:16
+    run.run_to(0x000e, 100_000);
+    assert_eq!(run.register(0x10), 0x80);
+    run.run_to(0x0019, 100_000);
+    assert_eq!(run.register(0x10), 0x31);
+
+    // External clock on RA4 without and with prescaler
+    run.run_to(0x001e, 100);
+    for i in 0..40 {
+        run.cpu.data_bus.set_porta_pin(RA4, i % 2 == 0);
+        run.step();
+    }
+    run.run_to(0x0025, 100);
+    for i in 0..80 {
+        run.cpu.data_bus.set_porta_pin(RA4, i % 2 == 0);
+        run.step();
+    }
+    run.run_to(0x0027, 100);
+    run.finish();
+}
+
+#[test]
+fn tpicsim8_interrupts() {
+    let mut run = GoldenRun::load("TPicSim8");
+    run.run_to(0x002c, 100_000);
+
+    // RB0/INT triggers on the falling edge only
+    run.step_n(10);
+    run.cpu.data_bus.set_portb_pin(RB0, true);
+    run.step_n(10);
+    assert_eq!(run.cpu.data_bus.get_pc() & 0xfe, 0x2c);
+    run.cpu.data_bus.set_portb_pin(RB0, false);
+    run.run_to(0x0031, 100);
+
+    run.step_n(10);
+    run.cpu.data_bus.set_portb_pin(RB4, true);
+    run.run_to(0x003a, 100);
+
+    // RB4-RB6 are outputs now and must not trigger the interrupt
+    run.step_n(10);
+    run.cpu.data_bus.set_portb_pin(RB5, true);
+    run.step_n(10);
+    assert_eq!(run.cpu.data_bus.get_pc() & 0xfe, 0x3a);
+    run.cpu.data_bus.set_portb_pin(RB7, true);
+    run.run_to(0x003c, 100);
+
+    assert_eq!(run.register(0x20), 0x54);
+    assert_eq!(run.register(0x21), 0x49);
+    assert_eq!(run.register(0x22), 0x52);
+    assert_eq!(run.register(0x23), 0x00);
+    run.finish();
+}
+
+#[test]
+fn tpicsim9_sleep() {
+    let mut run = GoldenRun::load("TPicSim9");
+    run.run_to(0x0003, 100);
+    run.step();
+    assert!(run.cpu.sleeping);
+
+    // The watchdog wakes the cpu after about 2.3s
+    run.run_to(0x0006, 3_000_000);
+    assert_eq!(run.register(0x10), 0xc1);
+    assert_eq!(run.register(0x11), 0x40);
+    run.finish();
+}
+
+#[test]
+fn tpicsim10_computed_jumps() {
+    let mut run = GoldenRun::load("TPicSim10");
+    run.run_to(0x000f, 1000);
+    assert_eq!(&run.cpu.data_bus.memory[0x10..0x15], &[0x60, 0x61, 0x62, 0x63, 0x64]);
+
+    // Without PCLATH the ADDWF PCL lands at 0Fh instead of the table
+    run.run_to(0x0109, 100);
+    run.step();
+    assert_eq!(run.cpu.data_bus.get_pc(), 0x000f);
+    run.finish();
+}
+
+#[test]
+fn tpicsim11_watchdog_reset() {
+    let mut run = GoldenRun::load("TPicSim11");
+    run.run_to(0x0008, 100);
+    run.run_to(0x0000, 100_000);
+
+    // In 21h steht ca. 17h, TO shows the watchdog reset
+    assert_eq!(run.register(0x21), 0x17);
+    assert!(!get_bit(run.cpu.data_bus.sfr_bank.status, TO));
+    assert!(get_bit(run.cpu.data_bus.sfr_bank.status, PD));
+    run.finish();
+}
+
+#[test]
+fn tpicsim12_eeprom() {
+    let mut run = GoldenRun::load("TPicSim12");
+    run.run_to(0x0023, 1_000_000);
+
+    for (address, value) in run.cpu.data_bus.eeprom.data.iter().enumerate() {
+        assert_eq!(*value, !(address as u8));
+    }
+    assert_eq!(run.cpu.data_bus.sfr_bank.eedata, 0xc0);
+    run.finish();
+}
+
+#[test]
+fn tpicsim13_running_light() {
+    let mut run = GoldenRun::load("TPicSim13");
+    let mut pattern = Vec::new();
+
+    // RA0 = 0 shifts to the left
+    for _ in 0..10 {
+        run.run_to(0x0005, 100);
+        pattern.push(run.cpu.data_bus.sfr_bank.portb);
+        run.step();
+    }
+    assert_eq!(pattern, [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x01]);
+
+    run.cpu.data_bus.set_porta_pin(RA0, true);
+    pattern.clear();
+    for _ in 0..3 {
+        run.run_to(0x0005, 100);
+        pattern.push(run.cpu.data_bus.sfr_bank.portb);
+        run.step();
+    }
+    // The pass which already tested RA0 still shifts to the left
+    assert_eq!(pattern, [0x02, 0x01, 0x80]);
+    run.finish();
+}
+
+#[test]
+fn tpicsim14_light_bar() {
+    let mut run = GoldenRun::load("TPicSim14");
+    let mut pattern = Vec::new();
+
+    for _ in 0..10 {
+        run.run_to(0x0004, 100);
+        pattern.push(run.cpu.data_bus.sfr_bank.portb);
+        run.step();
+    }
+    assert_eq!(pattern, [0x00, 0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff, 0x00]);
+    run.finish();
+}
+
+#[test]
+fn tpicsim15_port_latches() {
+    let mut run = GoldenRun::load("TPicSim15");
+
+    // RA0 and RB7 follow their latches while they are outputs
+    run.run_to(0x0012, 100);
+    assert!(get_bit(run.cpu.data_bus.port_a.pins(), RA0));
+    run.run_to(0x0014, 100);
+    assert!(!get_bit(run.cpu.data_bus.port_a.pins(), RA0));
+    run.run_to(0x0016, 100);
+    assert!(!get_bit(run.cpu.data_bus.port_b.port.pins(), RB7));
+    run.run_to(0x0018, 100);
+    assert!(get_bit(run.cpu.data_bus.port_b.port.pins(), RB7));
+
+    // As input RA0 only changes the latch, which shows up once it is an output again
+    run.run_to(0x001f, 100);
+    assert!(!get_bit(run.cpu.data_bus.port_a.pins(), RA0));
+    assert!(get_bit(run.cpu.data_bus.sfr_bank.porta, RA0));
+    run.run_to(0x0024, 100);
+    assert!(get_bit(run.cpu.data_bus.port_a.pins(), RA0));
+
+    run.cpu.data_bus.set_portb_pin(RB7, true);
+    run.run_to(0x002c, 100);
+    assert!(get_bit(run.cpu.data_bus.port_b.port.pins(), RB7));
+    assert!(!get_bit(run.cpu.data_bus.sfr_bank.portb, RB7));
+    run.run_to(0x0030, 100);
+    assert!(!get_bit(run.cpu.data_bus.port_b.port.pins(), RB7));
+    run.finish();
+}
+
+#[test]
+fn listing_comments_are_parsed() {
+    assert_eq!(
+        parse_expectation("W=10h, FSR=10h, wert2=?? , DC=x, C=1, Z=0;"),
+        vec![
+            (String::from("W"), 0x10),
+            (String::from("FSR"), 0x10),
+            (String::from("C"), 0x01),
+            (String::from("Z"), 0x00)
+        ]
+    );
+    assert_eq!(parse_expectation("wert1=08h, wert1=07h, ... DC,C und Z"), vec![(String::from("wert1"), 0x08)]);
+    assert_eq!(parse_expectation("W=20h, 21h, 22h, etc"), vec![]);
+    assert_eq!(parse_number("0ch"), Some(0x0c));
+}
@@ -0,0 +1,101 @@
+use super::bits::*;
+use super::event::*;
+use super::stack::StackError;
+
+// Translates an event into the lines of the GUI_PicSim text protocol, events
+// the gui has no display for produce no lines
+pub fn to_protocol(event: &SimEvent) -> Vec<String> {
+    match event {
+        SimEvent::RegisterChanged { address, value } => vec![format!("FREG {},0x{:02x}", address, value)],
+        SimEvent::WChanged(value) => vec![format!("WREG {:02x}h", value)],
+        SimEvent::StatusChanged(value) => {
+            let mut lines = vec![format!("STATUS {:02x}h", value)];
+            lines.extend(bit_lines("STATUSBIT", *value, 8));
+            lines
+        }
+        SimEvent::IntconChanged(value) => bit_lines("INTCONBIT", *value, 8),
+        SimEvent::OptionChanged(value) => vec![format!("OPTION {:02x}h", value)],
+        SimEvent::FsrChanged(value) => vec![format!("FSR {:02x}h", value)],
+        SimEvent::Timer0Changed(value) => vec![format!("TIMER0 {:02x}h", value)],
+        SimEvent::PcChanged { pc, pcl, pclath } => vec![
+            format!("PCL {:02x}h", pcl),
+            format!("PCLATH {:02x}h", pclath),
+            format!("PCINTERN {:04}", pc),
+        ],
+        SimEvent::LineLeft(line) => vec![format!("RESLINE {}", line)],
+        SimEvent::LineEntered(line) => vec![format!("SETLINE {}", line)],
+        SimEvent::StackChanged(entries) if entries.is_empty() => vec![String::from("STACK")],
+        SimEvent::StackChanged(entries) => {
+            let entries: Vec<String> = entries.iter().map(|pc| format!("{:04}", pc)).collect();
+            vec![format!("STACK {}", entries.join(", "))]
+        }
+        SimEvent::StackError { error, address } => {
+            let kind = match error {
+                StackError::Overflow => "OVERFLOW",
+                StackError::Underflow => "UNDERFLOW",
+            };
+            vec![format!("STACKERROR {} {:04x}h", kind, address)]
+        }
+        SimEvent::PinChanged { port, bit, level } => {
+            vec![format!("{} {},{}", port_name("PORT", *port), bit, *level as u8)]
+        }
+        SimEvent::DirectionChanged { port, bit, input } => {
+            vec![format!("{} {},{}", port_name("TRIS", *port), bit, *input as u8)]
+        }
+        SimEvent::PrescalerChanged { ratio } => vec![format!("PRECALER 1:{}", ratio)],
+        SimEvent::WatchdogChanged { remaining: Some(remaining) } => {
+            vec![format!("WATCHDOG {:.3}ms", remaining.as_secs_f64() * 1000.0)]
+        }
+        SimEvent::WatchdogChanged { remaining: None } => vec![String::from("WATCHDOG ---")],
+        SimEvent::Halted => vec![String::from("SLEEP 1")],
+        SimEvent::WokeUp => vec![String::from("SLEEP 0")],
+        SimEvent::BreakpointHit(reason) => vec![format!("BREAK {}", reason)],
+        SimEvent::InterruptEntered { .. } | SimEvent::Reset(_) => vec![],
+    }
+}
+
+fn bit_lines(name: &str, value: u8, width: usize) -> Vec<String> {
+    (0..width).map(|bit| format!("{} {},{}", name, bit, get_bit(value, bit) as u8)).collect()
+}
+
+fn port_name(prefix: &str, port: PortId) -> String {
+    match port {
+        PortId::A => format!("{}A", prefix),
+        PortId::B => format!("{}B", prefix),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers_are_formatted_like_the_gui_expects() {
+        assert_eq!(to_protocol(&SimEvent::RegisterChanged { address: 12, value: 0x0a }), vec!["FREG 12,0x0a"]);
+        assert_eq!(to_protocol(&SimEvent::WChanged(0xf3)), vec!["WREG f3h"]);
+    }
+
+    #[test]
+    fn status_is_sent_with_every_bit() {
+        let lines = to_protocol(&SimEvent::StatusChanged(0x19));
+        assert_eq!(lines.len(), 9);
+        assert_eq!(lines[0], "STATUS 19h");
+        assert_eq!(lines[1], "STATUSBIT 0,1");
+        assert_eq!(lines[2], "STATUSBIT 1,0");
+        assert_eq!(lines[5], "STATUSBIT 4,1");
+        assert_eq!(lines[8], "STATUSBIT 7,0");
+    }
+
+    #[test]
+    fn the_pc_is_sent_as_pcl_pclath_and_decimal_pcintern() {
+        let lines = to_protocol(&SimEvent::PcChanged { pc: 0x0123, pcl: 0x23, pclath: 0x01 });
+        assert_eq!(lines, vec!["PCL 23h", "PCLATH 01h", "PCINTERN 0291"]);
+    }
+
+    #[test]
+    fn stack_entries_are_separated_by_commas() {
+        assert_eq!(to_protocol(&SimEvent::StackChanged(vec![])), vec!["STACK"]);
+        assert_eq!(to_protocol(&SimEvent::StackChanged(vec![7])), vec!["STACK 0007"]);
+        assert_eq!(to_protocol(&SimEvent::StackChanged(vec![7, 300])), vec!["STACK 0007, 0300"]);
+    }
+}
@@ -0,0 +1,156 @@
+// Intel HEX record types
+const DATA_RECORD: u8 = 0x00;
+const EOF_RECORD: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS_RECORD: u8 = 0x02;
+const EXTENDED_LINEAR_ADDRESS_RECORD: u8 = 0x04;
+
+// Maximum number of data bytes per record when writing hex files
+const RECORD_LENGTH: usize = 16;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HexRecord {
+    // Absolute byte address of the first data byte
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+pub fn is_hex_file(content: &[u8]) -> bool {
+    content.iter().find(|c| !c.is_ascii_whitespace()) == Some(&b':')
+}
+
+// Parses all data records of an Intel HEX file (INHX8M or INHX32)
+pub fn parse_hex_file(content: &str) -> Result<Vec<HexRecord>, String> {
+    let mut records = Vec::new();
+    let mut base_address = 0u32;
+
+    for (line_number, line) in (1..).zip(content.lines()) {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let bytes = parse_record(line).map_err(|e| format!("Line {}: {}", line_number, e))?;
+        let length = bytes[0] as usize;
+        let offset = ((bytes[1] as u32) << 8) | bytes[2] as u32;
+        let record_type = bytes[3];
+        let data = &bytes[4..4 + length];
+
+        match record_type {
+            DATA_RECORD => records.push(HexRecord {
+                address: base_address + offset,
+                data: data.to_vec(),
+            }),
+            EOF_RECORD => return Ok(records),
+            EXTENDED_SEGMENT_ADDRESS_RECORD if length == 2 => {
+                base_address = (((data[0] as u32) << 8) | data[1] as u32) << 4;
+            }
+            EXTENDED_LINEAR_ADDRESS_RECORD if length == 2 => {
+                base_address = (((data[0] as u32) << 8) | data[1] as u32) << 16;
+            }
+            // Start address records have no meaning for the pic
+            0x03 | 0x05 => {}
+            _ => {
+                return Err(format!(
+                    "Line {}: Invalid record type {:02x} with length {}",
+                    line_number, record_type, length
+                ))
+            }
+        }
+    }
+
+    Err(String::from("Missing end of file record"))
+}
+
+// Writes the records as Intel HEX file including the end of file record
+pub fn write_hex_file(records: &[HexRecord]) -> String {
+    let mut result = String::new();
+    let mut upper_address = 0u16;
+
+    for record in records {
+        for (i, chunk) in record.data.chunks(RECORD_LENGTH).enumerate() {
+            let address = record.address + (i * RECORD_LENGTH) as u32;
+
+            if (address >> 16) as u16 != upper_address {
+                upper_address = (address >> 16) as u16;
+                let upper = upper_address.to_be_bytes();
+                result += &format_record(0, EXTENDED_LINEAR_ADDRESS_RECORD, &upper);
+            }
+
+            result += &format_record(address as u16, DATA_RECORD, chunk);
+        }
+    }
+
+    result + &format_record(0, EOF_RECORD, &[])
+}
+
+fn parse_record(line: &str) -> Result<Vec<u8>, String> {
+    if !line.starts_with(':') {
+        return Err(String::from("Record does not start with ':'"));
+    }
+
+    let bytes = hex::decode(&line[1..]).map_err(|e| format!("Invalid hex digits ({})", e))?;
+
+    if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+        return Err(String::from("Record length does not match its byte count"));
+    }
+
+    let checksum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    if checksum != 0 {
+        return Err(format!("Checksum mismatch (off by {:02x})", checksum));
+    }
+
+    Ok(bytes)
+}
+
+fn format_record(address: u16, record_type: u8, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&address.to_be_bytes());
+    bytes.push(record_type);
+    bytes.extend_from_slice(data);
+
+    let checksum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_sub(*byte));
+    bytes.push(checksum);
+
+    format!(":{}\n", hex::encode_upper(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{parse_hex_program, parse_lst_file, Cpu};
+    use std::fs;
+
+    #[test]
+    fn hex_image_matches_the_listing() {
+        let listing = fs::read_to_string("programs/TPicSim1.LST").expect("Failed to read listing");
+        let program = parse_lst_file(&listing).expect("Failed to parse listing").program;
+
+        // Hex files store every word little endian at twice its word address
+        let code = program.chunks(2).flat_map(|word| vec![word[1], word[0]]).collect();
+        let hex = write_hex_file(&[
+            HexRecord { address: 0, data: code },
+            HexRecord { address: 0x2001 * 2, data: vec![0x34, 0x12] },
+            HexRecord { address: 0x2007 * 2, data: vec![0xfb, 0x3f] },
+            HexRecord { address: 0x2102 * 2, data: vec![0x5a, 0x00] },
+        ]);
+
+        let image = parse_hex_program(&hex).expect("Failed to parse hex image");
+        assert_eq!(image.program, program);
+        assert_eq!(image.id_locations, Some([0x3fff, 0x1234, 0x3fff, 0x3fff]));
+        assert_eq!(image.config_word, Some(0x3ffb));
+        assert_eq!(image.eeprom.map(|eeprom| eeprom[..3].to_vec()), Some(vec![0xff, 0xff, 0x5a]));
+
+        let mut cpu = Cpu::new();
+        cpu.load(image);
+        assert!(!cpu.rom_bus.watchdog_enabled());
+        assert_eq!(cpu.eeprom().data[2], 0x5a);
+
+        // A flipped digit breaks the checksum of the first record
+        let corrupted = hex.replacen("30", "31", 1);
+        let error = parse_hex_program(&corrupted).err().expect("Corrupted hex image was accepted");
+        assert!(error.starts_with("Line 1: Checksum mismatch"), "{}", error);
+
+        let outside = write_hex_file(&[HexRecord { address: 0x2004 * 2, data: vec![0, 0] }]);
+        assert!(parse_hex_program(&outside).is_err());
+    }
+}
@@ -17,8 +17,10 @@ pub struct Literal(pub u8);
 pub enum InstructionCategory {
     ByteOriented,
     BitOriented,
-    LiteralOriented,
-    AddressOriented,
+    // Literal and control operations
+    Literal,
+    // CALL and GOTO with an 11 bit address
+    Jump,
 }
 
 impl InstructionCategory {
@@ -26,8 +28,8 @@ impl InstructionCategory {
         match value {
             0b00 => InstructionCategory::ByteOriented,
             0b01 => InstructionCategory::BitOriented,
-            0b11 => InstructionCategory::LiteralOriented,
-            0b10 => InstructionCategory::AddressOriented,
+            0b11 => InstructionCategory::Literal,
+            0b10 => InstructionCategory::Jump,
             _ => unreachable!("Invalid instruction category: {:02x}", value),
         }
     }
@@ -138,13 +140,13 @@ impl Instruction {
                     _ => Err(format!("Unknown opcode: {:04x}", opcode)),
                 }
             }
-            InstructionCategory::LiteralOriented => {
+            InstructionCategory::Literal => {
                 let literal = Literal((opcode & 0b11111111) as u8);
                 let selector = ((opcode >> 8) & 0b1111) as u8;
 
                 match selector {
-                    0b0000 | 0b0001 | 0b0010 | 0b0011 => Ok(Instruction::MovLw(literal)),
-                    0b0100 | 0b0101 | 0b0110 | 0b0111 => Ok(Instruction::RetLw(literal)),
+                    0b0000..=0b0011 => Ok(Instruction::MovLw(literal)),
+                    0b0100..=0b0111 => Ok(Instruction::RetLw(literal)),
                     0b1100 | 0b1101 => Ok(Instruction::SubLw(literal)),
                     0b1111 | 0b1110 => Ok(Instruction::AddLw(literal)),
                     0b1010 => Ok(Instruction::XorLw(literal)),
@@ -153,7 +155,7 @@ impl Instruction {
                     _ => Err(format!("Unknown opcode: {:04x}", opcode)),
                 }
             }
-            InstructionCategory::AddressOriented => {
+            InstructionCategory::Jump => {
                 let address = Address(opcode & 0b111_1111_1111);
                 let selector = ((opcode >> 11) & 0b1) as u8;
 
@@ -0,0 +1,19 @@
+use super::bits::*;
+use super::data_bus::SfrBank;
+
+pub const INTERRUPT_VECTOR: u16 = 0x0004;
+
+// Returns true if any enabled interrupt source has its flag set (GIE is not checked)
+pub fn interrupt_pending(sfr_bank: &SfrBank) -> bool {
+    let intcon = sfr_bank.intcon;
+
+    (get_bit(intcon, T0IE) && get_bit(intcon, T0IF))
+        || (get_bit(intcon, INTE) && get_bit(intcon, INTF))
+        || (get_bit(intcon, RBIE) && get_bit(intcon, RBIF))
+        || (get_bit(intcon, EEIE) && get_bit(sfr_bank.eecon1, EEIF))
+}
+
+// Returns true if the cpu has to vector to the interrupt service routine
+pub fn interrupt_requested(sfr_bank: &SfrBank) -> bool {
+    get_bit(sfr_bank.intcon, GIE) && interrupt_pending(sfr_bank)
+}
@@ -1,13 +1,44 @@
+mod alu;
+mod assembler;
 mod bits;
+mod breakpoint;
+#[cfg(test)]
+mod conformance;
 mod cpu;
 mod data_bus;
+mod disassembler;
+mod eeprom;
+mod event;
+#[cfg(test)]
+mod golden;
+mod gui_protocol;
+mod hex;
 mod instruction;
+mod interrupt;
 mod rom_bus;
 mod parser;
+mod program_info;
+mod port;
+mod stack;
+mod timer;
+mod watchdog;
 
+pub use assembler::*;
 pub use bits::*;
+pub use breakpoint::*;
 pub use cpu::*;
 pub use data_bus::*;
+pub use disassembler::*;
+pub use eeprom::*;
+pub use event::*;
+pub use gui_protocol::*;
+pub use hex::*;
 pub use instruction::*;
+pub use interrupt::*;
 pub use rom_bus::*;
-pub use parser::*;
\ No newline at end of file
+pub use parser::*;
+pub use program_info::*;
+pub use port::*;
+pub use stack::*;
+pub use timer::*;
+pub use watchdog::*;
\ No newline at end of file
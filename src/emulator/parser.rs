@@ -1,40 +1,362 @@
+use super::assembler::assemble_program;
 use super::bits::*;
+use super::eeprom::*;
+use super::hex::*;
+use super::program_info::*;
+use super::rom_bus::*;
 
 use regex::Regex;
-use std::collections::HashMap;
-use std::io::BufRead;
+use std::collections::BTreeMap;
+
+// Value of an erased program memory, id location or configuration word
+const ERASED_WORD: u16 = 0x3fff;
+const ERASED_EEPROM_BYTE: u8 = 0xff;
+
+pub const MNEMONICS: [&str; 35] = [
+    "addwf", "andwf", "clrf", "clrw", "comf", "decf", "decfsz", "incf", "incfsz", "iorwf", "movf", "movwf",
+    "nop", "rlf", "rrf", "subwf", "swapf", "xorwf", "bcf", "bsf", "btfsc", "btfss", "addlw", "andlw", "call",
+    "clrwdt", "goto", "iorlw", "movlw", "retfie", "retlw", "return", "sleep", "sublw", "xorlw",
+];
+pub const DIRECTIVES: [&str; 16] = [
+    "org", "equ", "set", "end", "list", "nolist", "device", "processor", "radix", "include", "__config",
+    "cblock", "endc", "errorlevel", "de", "dt",
+];
 
 pub struct ParseResult {
-    pub pc_mapper: HashMap<u16, usize>,
-    pub program: Vec<u8>
+    pub info: ProgramInfo,
+    pub program: Vec<u8>,
+    // Configuration word (0x2007), None keeps the current fuses
+    pub config_word: Option<u16>,
+    // ID locations (0x2000-0x2003), None keeps the current ones
+    pub id_locations: Option<[u16; ID_LOCATIONS]>,
+    // Eeprom data (0x2100), None keeps the current content
+    pub eeprom: Option<[u8; EEPROM_SIZE]>,
 }
 
 impl ParseResult {
     pub fn new() -> Self {
         Self {
-            pc_mapper: HashMap::new(),
+            info: ProgramInfo::new(),
             program: Vec::new(),
+            config_word: None,
+            id_locations: None,
+            eeprom: None,
+        }
+    }
+}
+
+impl Default for ParseResult {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Parses the listing of MPASM, MPLAB X or gpasm. Every opcode is placed at the address
+// listed in front of it, the source text follows the five digit line number.
+pub fn parse_lst_file(data: &str) -> Result<ParseResult, String> {
+    // Address, one or more words (several for de/dt/data) and the line number
+    let opcode_rgx = Regex::new(r"^([0-9A-Fa-f]{4,6})\s+((?:[0-9A-Fa-f]{4}\s+)+)(\d{5})(?:\s(.*))?$").unwrap();
+    // Lines without code, equ shows its value in front of the line number
+    let source_rgx = Regex::new(r"^\s*(?:[0-9A-Fa-f]{8}\s+)?(\d{5})(?:\s(.*))?$").unwrap();
+    let address_rgx = Regex::new(r"^[0-9A-Fa-f]{4,6}\s").unwrap();
+
+    let mut info = ProgramInfo::new();
+    // Word and listing line per word address
+    let mut words: BTreeMap<u32, (u16, usize)> = BTreeMap::new();
+    let mut config_word = None;
+    // Address the next opcode is placed at, labels on a line of their own point there
+    let mut location = 0;
+    let mut symbol_table = false;
+
+    for (current_line, line) in (1..).zip(data.lines()) {
+        // MPASM appends a table of all symbols with their values as eight hex digits
+        if line.trim_start().starts_with("SYMBOL TABLE") {
+            symbol_table = true;
+            continue;
+        }
+        if symbol_table {
+            if let [name, value] = line.split_whitespace().collect::<Vec<&str>>()[..] {
+                if let (8, Ok(value)) = (value.len(), u16::from_str_radix(value, 16)) {
+                    if !info.labels.contains_key(name) {
+                        info.add_symbol(name, value);
+                    }
+                }
+            }
+            continue;
+        }
+
+        if let Some(cap) = opcode_rgx.captures(line) {
+            let address = u32::from_str_radix(&cap[1], 16).map_err(|e| format!("Line {}: {}", current_line, e))?;
+            let (text, comment) = split_comment(cap.get(4).map_or("", |m| m.as_str()));
+
+            for (offset, word) in cap[2].split_whitespace().enumerate() {
+                let word = u16::from_str_radix(word, 16).map_err(|e| format!("Line {}: {}", current_line, e))?;
+                let word_address = address + offset as u32;
+
+                if word_address < ID_LOCATIONS_ADDRESS && word > ERASED_WORD {
+                    return Err(format!("Line {}: Opcode {:04x} does not fit into 14 bits", current_line, word));
+                }
+                if let Some((_, line)) = words.insert(word_address, (word, current_line)) {
+                    return Err(format!(
+                        "Line {}: Address {:04x}h is already used by line {}",
+                        current_line, word_address, line
+                    ));
+                }
+            }
+
+            if address < ID_LOCATIONS_ADDRESS {
+                let address = address as u16;
+                info.lines.insert(address, SourceLine {
+                    line: current_line,
+                    text: String::from(text),
+                    comment,
+                });
+                if let Some(label) = text.split_whitespace().next().and_then(label_name) {
+                    info.add_label(label, address);
+                }
+
+                location = address + cap[2].split_whitespace().count() as u16;
+            }
+            continue;
         }
+
+        let source = match source_rgx.captures(line) {
+            Some(cap) => cap.get(2).map_or("", |m| m.as_str()),
+            None if address_rgx.is_match(line) => {
+                return Err(format!("Line {}: Malformed address or opcode: {}", current_line, line.trim()))
+            }
+            // Page headers, column titles and assembler messages
+            None => continue,
+        };
+        let tokens: Vec<&str> = split_comment(source).0.split_whitespace().collect();
+
+        match tokens[..] {
+            [name, directive, value, ..] if directive.eq_ignore_ascii_case("equ") => {
+                if let Some(value) = parse_value(value) {
+                    info.add_symbol(name, value);
+                }
+            }
+            [directive, value, ..] if directive.eq_ignore_ascii_case("org") => {
+                location = parse_value(value)
+                    .ok_or_else(|| format!("Line {}: Invalid org address {}", current_line, value))?;
+            }
+            // Only plain numbers are known here, the assembler usually lists the word at 2007 anyway
+            [directive, value] if directive.eq_ignore_ascii_case("__config") => {
+                config_word = parse_value(value).or(config_word);
+            }
+            [token] => {
+                if let Some(label) = label_name(token) {
+                    info.add_label(label, location);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut result = program_image(&words)?;
+    result.config_word = result.config_word.or(config_word);
+    result.info = info;
+    Ok(result)
+}
+
+// Places the words of a listing or source file, each one with the line it comes from
+pub(crate) fn program_image(words: &BTreeMap<u32, (u16, usize)>) -> Result<ParseResult, String> {
+    let mut result = ParseResult::new();
+    result.program = erased_program(words.keys().copied());
+
+    for (&address, &(word, line)) in words {
+        place_word(&mut result, address, word).map_err(|e| format!("Line {}: {}", line, e))?;
+    }
+
+    Ok(result)
+}
+
+// Parses a number in one of the MPASM notations (0x0c, 0ch, h'0c', d'12', b'1100', 1100b, .12),
+// plain numbers are hex like with the default radix
+pub fn parse_value(text: &str) -> Option<u16> {
+    let text = text.to_lowercase();
+    let quoted = |prefix: char| {
+        text.strip_prefix(prefix)
+            .and_then(|t| t.strip_prefix('\''))
+            .and_then(|t| t.strip_suffix('\''))
+    };
+
+    let (digits, radix) = if let Some(digits) = text.strip_prefix("0x") {
+        (digits, 16)
+    } else if let Some(digits) = quoted('h') {
+        (digits, 16)
+    } else if let Some(digits) = quoted('d') {
+        (digits, 10)
+    } else if let Some(digits) = quoted('b') {
+        (digits, 2)
+    } else if let Some(digits) = text.strip_prefix('.') {
+        (digits, 10)
+    } else if let Some(digits) = text.strip_suffix('b').filter(|d| !d.is_empty() && d.chars().all(|c| c == '0' || c == '1')) {
+        // 00111000b is binary, other numbers ending with b are hex
+        (digits, 2)
+    } else {
+        (text.strip_suffix('h').unwrap_or(&text), 16)
+    };
+
+    u16::from_str_radix(digits, radix).ok()
+}
+
+// Splits the source text of a listing line at the start of its comment
+pub fn split_comment(source: &str) -> (&str, Option<String>) {
+    match source.split_once(';') {
+        Some((text, comment)) => (text.trim(), Some(String::from(comment.trim()))),
+        None => (source.trim(), None),
+    }
+}
+
+// A label is any identifier at the start of a line, which is neither an instruction nor a directive
+pub fn label_name(token: &str) -> Option<&str> {
+    let name = token.strip_suffix(':').unwrap_or(token);
+    let lower = name.to_lowercase();
+
+    let identifier = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    let reserved = MNEMONICS.contains(&lower.as_str()) || DIRECTIVES.contains(&lower.as_str());
+
+    if identifier && !reserved {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+// Loads a program in any supported format, hex files are recognized by their content
+// and source files by the .asm extension, everything else is read as listing
+pub fn parse_program(name: &str, content: &str) -> Result<ParseResult, String> {
+    if is_hex_file(content.as_bytes()) {
+        parse_hex_program(content)
+    } else if name.to_lowercase().ends_with(".asm") {
+        assemble_program(content)
+    } else {
+        parse_lst_file(content)
     }
 }
 
-pub fn parse_lst_file(data: &str) -> ParseResult {
+// Loads an Intel HEX file (INHX8M or INHX32). Every word is stored little endian
+// at twice its word address, eeprom bytes are the low bytes of their words.
+pub fn parse_hex_program(content: &str) -> Result<ParseResult, String> {
+    let mut words: BTreeMap<u32, u16> = BTreeMap::new();
+
+    for record in parse_hex_file(content)? {
+        for (address, value) in (record.address..).zip(&record.data) {
+            let word = words.entry(address / 2).or_insert(0);
+            *word |= (*value as u16) << (8 * (address % 2));
+        }
+    }
+
     let mut result = ParseResult::new();
-    let mut current_line = 1;
-    let command_rgx = Regex::new(r"^([0-9A-F]{4})\s([0-9A-F]{4})").unwrap();
+    result.program = erased_program(words.keys().copied());
+
+    for (&address, &word) in &words {
+        place_word(&mut result, address, word)?;
+    }
+
+    Ok(result)
+}
 
-    for line in data.lines() {
-        for cap in command_rgx.captures(line) {
-            let index = u16::from_str_radix(&cap[1], 16).unwrap();
-            let opcode = u16::from_str_radix(&cap[2], 16).unwrap();
+// Program memory up to the highest used address, gaps between the code stay erased
+fn erased_program(addresses: impl Iterator<Item = u32>) -> Vec<u8> {
+    let words = addresses.filter(|address| *address < ID_LOCATIONS_ADDRESS).max().map_or(0, |address| address + 1);
+    [get_high_byte(ERASED_WORD), get_low_byte(ERASED_WORD)].repeat(words as usize)
+}
 
-            result.pc_mapper.insert(index, current_line);
-            result.program.push(get_high_byte(opcode));
-            result.program.push(get_low_byte(opcode));
+// Stores a word of a program image depending on the memory its address belongs to
+fn place_word(result: &mut ParseResult, address: u32, word: u16) -> Result<(), String> {
+    match address {
+        _ if address < ID_LOCATIONS_ADDRESS => {
+            let index = address as usize * 2;
+            result.program[index] = get_high_byte(word & ERASED_WORD);
+            result.program[index + 1] = get_low_byte(word & ERASED_WORD);
+        }
+        _ if address < ID_LOCATIONS_ADDRESS + ID_LOCATIONS as u32 => {
+            let id_locations = result.id_locations.get_or_insert([ERASED_WORD; ID_LOCATIONS]);
+            id_locations[(address - ID_LOCATIONS_ADDRESS) as usize] = word & ERASED_WORD;
+        }
+        CONFIG_WORD_ADDRESS => result.config_word = Some(word & ERASED_WORD),
+        _ if (EEPROM_HEX_ADDRESS..EEPROM_HEX_ADDRESS + EEPROM_SIZE as u32).contains(&address) => {
+            let eeprom = result.eeprom.get_or_insert([ERASED_EEPROM_BYTE; EEPROM_SIZE]);
+            eeprom[(address - EEPROM_HEX_ADDRESS) as usize] = get_low_byte(word);
         }
+        _ => {
+            return Err(format!(
+                "Word address {:04x}h is neither program memory, id location, configuration word nor eeprom",
+                address
+            ))
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn listing_symbols_and_labels() {
+        let listing = fs::read_to_string("programs/TPicSim4.LST").expect("Failed to read listing");
+        let info = parse_lst_file(&listing).expect("Failed to parse listing").info;
 
-        current_line += 1;
+        assert_eq!(info.symbols.get("wert1"), Some(&0x0c));
+        assert_eq!(info.symbol_for(0x0e), Some("ergeb"));
+        assert_eq!(info.labels.get("loop1"), Some(&0x0010));
+        assert_eq!(info.label_at(0x0000), Some("loop"));
+
+        let source = &info.lines[&0x0010];
+        assert_eq!((source.line, source.text.as_str()), (41, "addlw 1"));
+        assert_eq!(info.lines[&0x0000].comment.as_deref(), Some("in W steht nun 11h, DC=?, C=?, Z=?"));
+
+        // Labels may also share the line with an instruction
+        let listing = fs::read_to_string("programs/TPicSim2.LST").expect("Failed to read listing");
+        let info = parse_lst_file(&listing).expect("Failed to parse listing").info;
+        assert_eq!(info.label_at(0x0006), Some("up1"));
+        assert_eq!(info.lines[&0x0006].text, "up1      addlw 25h");
     }
 
-    result
-}
\ No newline at end of file
+    #[test]
+    fn listing_dialects_and_diagnostics() {
+        // gpasm: six digit lower case addresses, values of equ in front of the line number
+        let gpasm = "\
+LOC    OBJECT CODE    LINE  SOURCE TEXT
+  VALUE
+
+                      00001         processor 16f84
+  0000000c            00002 count   equ     0x0c
+002007 3ff2           00003         __config 0x3ff2
+                      00004         org     0x04
+000004 008c           00005 isr     movwf   count
+000005 0009           00006         retfie
+";
+        let result = parse_lst_file(gpasm).expect("Failed to parse gpasm listing");
+        assert_eq!(result.config_word, Some(0x3ff2));
+        assert_eq!(result.info.symbols.get("count"), Some(&0x0c));
+        assert_eq!(result.info.label_at(0x0004), Some("isr"));
+        // The org gap before the interrupt vector stays erased
+        assert_eq!(result.program, vec![0x3f, 0xff, 0x3f, 0xff, 0x3f, 0xff, 0x3f, 0xff, 0x00, 0x8c, 0x00, 0x09]);
+
+        // MPLAB X: several spaces between address and opcode, eeprom data listed at 2100
+        let mplab = "\
+2007   3FFB           00010     __CONFIG _CP_OFF & _WDT_OFF
+                      00011     org 0x2100
+2100   0011 0022      00012     de 0x11, 0x22
+                      00013     org 0
+0000   2800           00014 start goto start
+";
+        let result = parse_lst_file(mplab).expect("Failed to parse MPLAB X listing");
+        assert_eq!(result.config_word, Some(0x3ffb));
+        assert_eq!(result.eeprom.map(|eeprom| eeprom[..3].to_vec()), Some(vec![0x11, 0x22, 0xff]));
+        assert_eq!(result.info.line_of(0x0000), Some(5));
+
+        let duplicate = "0000 3011           00001  movlw 11h\n0000 3012           00002  movlw 12h\n";
+        assert_eq!(parse_lst_file(duplicate).err(), Some(String::from("Line 2: Address 0000h is already used by line 1")));
+
+        let malformed = "0000 30G1           00001  movlw 11h\n";
+        assert!(parse_lst_file(malformed).err().is_some_and(|e| e.starts_with("Line 1: Malformed")));
+    }
+}
@@ -0,0 +1,111 @@
+use super::bits::*;
+use super::data_bus::SfrBank;
+
+// Porta only has five pins (RA0-RA4)
+pub const PORTA_MASK: u8 = 0x1f;
+pub const PORTB_MASK: u8 = 0xff;
+// RA4 has an open drain output driver
+pub const PORTA_OPEN_DRAIN: u8 = 1 << RA4;
+
+// Only RB4-RB7 take part in the port change interrupt
+const RB_CHANGE_MASK: u8 = 0xf0;
+
+pub struct Port {
+    // Levels applied to the pins from outside
+    external: u8,
+    // Pins driven from outside, the others are floating
+    driven: u8,
+    open_drain: u8,
+    mask: u8,
+    pins: u8,
+}
+
+impl Port {
+    pub fn new(mask: u8, open_drain: u8) -> Self {
+        Self {
+            external: 0,
+            driven: 0,
+            open_drain,
+            mask,
+            pins: 0,
+        }
+    }
+
+    // Current level of all pins
+    pub fn pins(&self) -> u8 {
+        self.pins
+    }
+
+    // Treats all pins as driven from outside, floating pins default to low
+    pub fn drive_all(&mut self) {
+        self.driven = self.mask;
+    }
+
+    pub fn set_external(&mut self, bit: usize, level: bool) {
+        set_bit_enabled(&mut self.external, bit, level);
+        set_bit(&mut self.driven, bit);
+    }
+
+    // Recalculates the pin levels from the output latch, returns the previous levels
+    pub fn update(&mut self, latch: u8, tris: u8, pull_ups: bool) -> u8 {
+        let outputs = !tris;
+        let push_pull = outputs & !self.open_drain;
+        // Open drain outputs can only pull the pin low
+        let pulled_low = outputs & self.open_drain & !latch;
+        // Weak pull-ups only act on floating input pins
+        let pulled_up = if pull_ups { tris & !self.driven } else { 0 };
+
+        let external = (self.external & self.driven) | pulled_up;
+        let pins = ((latch & push_pull) | (external & !push_pull)) & !pulled_low;
+
+        let old = self.pins;
+        self.pins = pins & self.mask;
+        old
+    }
+}
+
+pub struct PortB {
+    pub port: Port,
+    // Pin levels the last time portb was read by the program
+    read_latch: u8,
+}
+
+impl PortB {
+    pub fn new() -> Self {
+        Self {
+            port: Port::new(PORTB_MASK, 0),
+            read_latch: 0,
+        }
+    }
+
+    // Recalculates the pins and detects RB0/INT edges and RB4-RB7 changes
+    pub fn update(&mut self, sfr_bank: &mut SfrBank) {
+        // Pull-ups are enabled when RBPU is cleared
+        let pull_ups = !get_bit(sfr_bank.option, RBPU);
+        let old = self.port.update(sfr_bank.portb, sfr_bank.trisb, pull_ups);
+        let pins = self.port.pins();
+
+        if get_bit(old ^ pins, RB0) {
+            // INTEDG selects the rising edge, otherwise the falling edge triggers
+            if get_bit(pins, RB0) == get_bit(sfr_bank.option, INTEDG) {
+                set_bit(&mut sfr_bank.intcon, INTF);
+            }
+        }
+
+        let inputs = sfr_bank.trisb & RB_CHANGE_MASK;
+        if (pins ^ self.read_latch) & inputs != 0 {
+            set_bit(&mut sfr_bank.intcon, RBIF);
+        }
+    }
+
+    // Reading portb ends the mismatch condition, RBIF has to be cleared in software
+    pub fn on_read(&mut self) {
+        self.read_latch = self.port.pins();
+    }
+}
+
+impl Default for PortB {
+    fn default() -> Self {
+        Self::new()
+    }
+}
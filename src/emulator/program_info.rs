@@ -0,0 +1,68 @@
+use std::collections::{BTreeMap, HashMap};
+
+// Listing line an opcode was assembled from
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SourceLine {
+    pub line: usize,
+    // Source text without the comment
+    pub text: String,
+    pub comment: Option<String>,
+}
+
+// Names and source lines of a program, taken from its listing
+pub struct ProgramInfo {
+    pub labels: HashMap<String, u16>,
+    // Values defined with equ (or found in the symbol table of the listing)
+    pub symbols: HashMap<String, u16>,
+    pub lines: HashMap<u16, SourceLine>,
+    // Reverse lookups, the first name defined for an address or value wins
+    label_names: BTreeMap<u16, String>,
+    symbol_names: BTreeMap<u16, String>,
+}
+
+impl ProgramInfo {
+    pub fn new() -> Self {
+        Self {
+            labels: HashMap::new(),
+            symbols: HashMap::new(),
+            lines: HashMap::new(),
+            label_names: BTreeMap::new(),
+            symbol_names: BTreeMap::new(),
+        }
+    }
+
+    pub fn add_label(&mut self, name: &str, address: u16) {
+        self.labels.entry(String::from(name)).or_insert(address);
+        self.label_names.entry(address).or_insert_with(|| String::from(name));
+    }
+
+    pub fn add_symbol(&mut self, name: &str, value: u16) {
+        self.symbols.entry(String::from(name)).or_insert(value);
+        self.symbol_names.entry(value).or_insert_with(|| String::from(name));
+    }
+
+    pub fn line_of(&self, address: u16) -> Option<usize> {
+        self.lines.get(&address).map(|source| source.line)
+    }
+
+    // Address of the opcode assembled from a listing line
+    pub fn address_of(&self, line: usize) -> Option<u16> {
+        self.lines.iter().find(|(_, source)| source.line == line).map(|(address, _)| *address)
+    }
+
+    // Label pointing to a program address, e.g. loop1 for 0010
+    pub fn label_at(&self, address: u16) -> Option<&str> {
+        self.label_names.get(&address).map(String::as_str)
+    }
+
+    // Symbol defined with a value, e.g. wert1 for 0x0c
+    pub fn symbol_for(&self, value: u16) -> Option<&str> {
+        self.symbol_names.get(&value).map(String::as_str)
+    }
+}
+
+impl Default for ProgramInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
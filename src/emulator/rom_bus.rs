@@ -2,12 +2,22 @@ use std::collections::HashMap;
 
 use super::bits::*;
 use super::instruction::*;
+use super::parser::ParseResult;
+
+// Unprogrammed configuration word (all fuses set)
+pub const DEFAULT_CONFIG_WORD: u16 = 0x3fff;
+// Word addresses of the configuration memory in hex files
+pub const ID_LOCATIONS_ADDRESS: u32 = 0x2000;
+pub const ID_LOCATIONS: usize = 4;
+pub const CONFIG_WORD_ADDRESS: u32 = 0x2007;
 
 pub struct RomBus {
     pub rom: [u8; 0xffff],
     min_rom_idx: u16,
     max_rom_idx: u16,
     cache: HashMap<u16, Instruction>,
+    pub config_word: u16,
+    pub id_locations: [u16; ID_LOCATIONS],
 }
 
 impl RomBus {
@@ -17,15 +27,28 @@ impl RomBus {
             min_rom_idx: 0,
             max_rom_idx: 0,
             cache: HashMap::new(),
+            config_word: DEFAULT_CONFIG_WORD,
+            id_locations: [DEFAULT_CONFIG_WORD; ID_LOCATIONS],
         }
     }
 
-    pub fn load_program(&mut self, program: &[u8], starting_address: u16) {
-        self.min_rom_idx = starting_address / 2;
-        self.max_rom_idx = self.min_rom_idx + (program.len() as u16 - 1) / 2;
-        for addr in 0..program.len() {
-            let rom_addr = addr + starting_address as usize;
-            self.rom[rom_addr] = program[addr];
+    // Programs the code starting at address 0 together with the configuration memory
+    pub fn load_program(&mut self, program: &ParseResult) {
+        // Nothing of the previous program may survive, not even its decoded instructions
+        self.rom = [0; 0xffff];
+        self.cache.clear();
+        self.rom[..program.program.len()].copy_from_slice(&program.program);
+
+        // A program without code leaves the minimum above the maximum, so no address can be fetched
+        let words = (program.program.len() as u16).div_ceil(2);
+        self.min_rom_idx = if words == 0 { 1 } else { 0 };
+        self.max_rom_idx = words.saturating_sub(1);
+
+        if let Some(config_word) = program.config_word {
+            self.config_word = config_word;
+        }
+        if let Some(id_locations) = program.id_locations {
+            self.id_locations = id_locations;
         }
     }
 
@@ -56,6 +79,23 @@ impl RomBus {
         }
     }
 
+    // Raw opcode at a program memory address, without decoding it
+    pub fn read_opcode(&self, index: u16) -> u16 {
+        self.read_word(index * 2)
+    }
+
+    pub fn watchdog_enabled(&self) -> bool {
+        self.config_word & (1 << WDTE) != 0
+    }
+
+    pub fn set_watchdog_enabled(&mut self, enabled: bool) {
+        if enabled {
+            self.config_word |= 1 << WDTE;
+        } else {
+            self.config_word &= !(1 << WDTE);
+        }
+    }
+
     pub fn get_rom_boundary(&self) -> (u16, u16) {
         (self.min_rom_idx, self.max_rom_idx)
     }
@@ -71,3 +111,26 @@ impl RomBus {
         join_bytes(self.read_byte(address), self.read_byte(address + 1))
     }
 }
+
+impl Default for RomBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::{parse_program, BreakReason, Cpu};
+
+    #[test]
+    fn loading_an_empty_program_removes_the_previous_one() {
+        let mut cpu = Cpu::new();
+        cpu.load(parse_program("movlw.asm", "  movlw 5\n").unwrap());
+        assert_eq!(cpu.rom_bus.get_rom_boundary(), (0, 0));
+
+        cpu.load(parse_program("empty.hex", ":00000001FF\n").unwrap());
+        assert!(matches!(cpu.step(), Some(BreakReason::InvalidOpcode { address: 0, .. })));
+        assert_eq!(cpu.w(), 0);
+        assert_eq!(cpu.rom_bus.read_opcode(0), 0);
+    }
+}
@@ -0,0 +1,126 @@
+// The pic16f84 has an eight level hardware stack
+pub const STACK_SIZE: usize = 8;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StackError {
+    // A ninth push overwrote the oldest return address
+    Overflow,
+    // A return without a matching call read a stale return address
+    Underflow,
+}
+
+// Circular return stack, the stack pointer silently wraps around in both directions
+pub struct Stack {
+    entries: [u16; STACK_SIZE],
+    // Index of the next free entry
+    pointer: usize,
+    // Number of valid return addresses, only used for diagnostics and display
+    depth: usize,
+}
+
+impl Stack {
+    pub fn new() -> Self {
+        Self {
+            entries: [0; STACK_SIZE],
+            pointer: 0,
+            depth: 0,
+        }
+    }
+
+    pub fn push(&mut self, value: u16) -> Result<(), StackError> {
+        self.entries[self.pointer] = value;
+        self.pointer = (self.pointer + 1) % STACK_SIZE;
+
+        if self.depth == STACK_SIZE {
+            Err(StackError::Overflow)
+        } else {
+            self.depth += 1;
+            Ok(())
+        }
+    }
+
+    // Always returns the entry below the stack pointer, the error tells whether it was valid
+    pub fn pop(&mut self) -> (u16, Result<(), StackError>) {
+        self.pointer = (self.pointer + STACK_SIZE - 1) % STACK_SIZE;
+        let value = self.entries[self.pointer];
+
+        if self.depth == 0 {
+            (value, Err(StackError::Underflow))
+        } else {
+            self.depth -= 1;
+            (value, Ok(()))
+        }
+    }
+
+    // Valid return addresses from the oldest to the most recent one
+    pub fn entries(&self) -> Vec<u16> {
+        (0..self.depth)
+            .map(|i| self.entries[(self.pointer + STACK_SIZE - self.depth + i) % STACK_SIZE])
+            .collect()
+    }
+}
+
+impl Default for Stack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full_stack() -> Stack {
+        let mut stack = Stack::new();
+        for value in 1..=STACK_SIZE as u16 {
+            assert_eq!(stack.push(value), Ok(()));
+        }
+        stack
+    }
+
+    #[test]
+    fn a_ninth_push_overwrites_the_oldest_entry() {
+        let mut stack = full_stack();
+        assert_eq!(stack.entries(), vec![1, 2, 3, 4, 5, 6, 7, 8]);
+
+        assert_eq!(stack.push(9), Err(StackError::Overflow));
+        assert_eq!(stack.entries(), vec![2, 3, 4, 5, 6, 7, 8, 9]);
+
+        for value in (2..=9).rev() {
+            assert_eq!(stack.pop(), (value, Ok(())));
+        }
+        assert!(stack.entries().is_empty());
+    }
+
+    #[test]
+    fn popping_an_empty_stack_returns_the_stale_entry() {
+        let mut stack = full_stack();
+        stack.push(9).unwrap_err();
+        for _ in 0..STACK_SIZE {
+            stack.pop().1.unwrap();
+        }
+
+        // The pointer wrapped around onto the entry overwritten by the ninth push
+        assert_eq!(stack.pop(), (9, Err(StackError::Underflow)));
+        assert_eq!(stack.pop(), (8, Err(StackError::Underflow)));
+        assert!(stack.entries().is_empty());
+    }
+
+    #[test]
+    fn entries_follow_the_wrapped_pointer() {
+        let mut stack = full_stack();
+        stack.push(9).unwrap_err();
+        stack.push(10).unwrap_err();
+        for value in [10, 9, 8] {
+            assert_eq!(stack.pop(), (value, Ok(())));
+        }
+        assert_eq!(stack.entries(), vec![3, 4, 5, 6, 7]);
+
+        // The second push wraps the pointer around the end of the array
+        stack.push(20).unwrap();
+        stack.push(21).unwrap();
+        assert_eq!(stack.entries(), vec![3, 4, 5, 6, 7, 20, 21]);
+        assert_eq!(stack.pop(), (21, Ok(())));
+        assert_eq!(stack.pop(), (20, Ok(())));
+    }
+}
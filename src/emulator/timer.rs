@@ -0,0 +1,124 @@
+use super::bits::*;
+use super::data_bus::SfrBank;
+
+// Number of instruction cycles in which TMR0 does not count after it was written
+const TMR0_INHIBIT_CYCLES: u8 = 2;
+
+pub struct Prescaler {
+    counter: u16,
+}
+
+impl Prescaler {
+    pub fn new() -> Self {
+        Self { counter: 0 }
+    }
+
+    pub fn clear(&mut self) {
+        self.counter = 0;
+    }
+
+    // Returns true every time the prescaler overflows
+    pub fn clock(&mut self, ratio: u16) -> bool {
+        self.counter += 1;
+
+        if self.counter >= ratio {
+            self.counter = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn assigned_to_wdt(option: u8) -> bool {
+        get_bit(option, PSA)
+    }
+
+    pub fn tmr0_ratio(option: u8) -> u16 {
+        2 << (option & 0b111)
+    }
+
+    pub fn wdt_ratio(option: u8) -> u16 {
+        1 << (option & 0b111)
+    }
+}
+
+impl Default for Prescaler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Timer0 {
+    inhibit: u8,
+    t0cki: bool,
+}
+
+impl Timer0 {
+    pub fn new() -> Self {
+        Self {
+            inhibit: 0,
+            t0cki: false,
+        }
+    }
+
+    // Called whenever the program writes to TMR0
+    pub fn on_write(&mut self, sfr_bank: &SfrBank, prescaler: &mut Prescaler) {
+        // The write takes effect in the current cycle, followed by the inhibit cycles
+        self.inhibit = TMR0_INHIBIT_CYCLES + 1;
+
+        if !Prescaler::assigned_to_wdt(sfr_bank.option) {
+            prescaler.clear();
+        }
+    }
+
+    // Advances the timer by one instruction cycle
+    pub fn tick(&mut self, sfr_bank: &mut SfrBank, prescaler: &mut Prescaler) {
+        if !get_bit(sfr_bank.option, T0CS) {
+            self.clock(sfr_bank, prescaler);
+        }
+
+        self.inhibit = self.inhibit.saturating_sub(1);
+    }
+
+    // Called whenever the level of the RA4/T0CKI pin changes
+    pub fn set_t0cki(&mut self, level: bool, sfr_bank: &mut SfrBank, prescaler: &mut Prescaler) {
+        let edge = self.t0cki != level;
+        self.t0cki = level;
+
+        if !edge || !get_bit(sfr_bank.option, T0CS) {
+            return;
+        }
+
+        // T0SE selects the falling edge, otherwise the rising edge increments
+        let falling = !level;
+        if falling == get_bit(sfr_bank.option, T0SE) {
+            self.clock(sfr_bank, prescaler);
+        }
+    }
+
+    fn clock(&mut self, sfr_bank: &mut SfrBank, prescaler: &mut Prescaler) {
+        // Without the prescaler TMR0 counts every clock (1:1)
+        if !Prescaler::assigned_to_wdt(sfr_bank.option)
+            && !prescaler.clock(Prescaler::tmr0_ratio(sfr_bank.option))
+        {
+            return;
+        }
+
+        // The prescaler keeps counting while the increment is inhibited
+        if self.inhibit > 0 {
+            return;
+        }
+
+        sfr_bank.tmr0 = sfr_bank.tmr0.wrapping_add(1);
+
+        if sfr_bank.tmr0 == 0 {
+            set_bit(&mut sfr_bank.intcon, T0IF);
+        }
+    }
+}
+
+impl Default for Timer0 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
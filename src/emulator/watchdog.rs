@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+use super::data_bus::SfrBank;
+use super::timer::Prescaler;
+
+// Nominal time-out period of the watchdog without prescaler
+pub const WDT_PERIOD: Duration = Duration::from_millis(18);
+
+pub struct Watchdog {
+    elapsed: Duration,
+}
+
+impl Watchdog {
+    pub fn new() -> Self {
+        Self {
+            elapsed: Duration::from_secs(0),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.elapsed = Duration::from_secs(0);
+    }
+
+    // Time until the watchdog times out (ignoring the prescaler)
+    pub fn remaining(&self) -> Duration {
+        WDT_PERIOD - self.elapsed
+    }
+
+    // Advances the watchdog by one instruction cycle and returns true on a time-out
+    pub fn tick(&mut self, cycle: Duration, sfr_bank: &SfrBank, prescaler: &mut Prescaler) -> bool {
+        self.elapsed += cycle;
+
+        if self.elapsed < WDT_PERIOD {
+            return false;
+        }
+
+        self.elapsed -= WDT_PERIOD;
+
+        if Prescaler::assigned_to_wdt(sfr_bank.option) {
+            prescaler.clock(Prescaler::wdt_ratio(sfr_bank.option))
+        } else {
+            true
+        }
+    }
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
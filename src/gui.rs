@@ -0,0 +1,250 @@
+use rssim::emulator::*;
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::{Instant, Duration};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Frontend for GUI_PicSim, translates its input commands into calls on the cpu
+pub struct GuiBridge {
+    pub cpu: Cpu,
+    pub input: Receiver<Vec<String>>,
+    pub running: bool,
+    pub eeprom_file: Option<PathBuf>,
+    // Eeprom content of the file, to notice completed writes of the program
+    saved_eeprom: [u8; EEPROM_SIZE],
+    last: Instant,
+    now: Instant,
+    frame_duration: Duration,
+}
+
+impl GuiBridge {
+    pub fn new(input: Receiver<Vec<String>>, output: Sender<Vec<String>>) -> Self {
+        // Only the pins the gui sets are driven, the weak pull-ups of portb act on the others
+        let mut cpu = Cpu::new();
+        cpu.add_observer(Box::new(ProtocolWriter { output }));
+
+        Self {
+            cpu,
+            input,
+            running: false,
+            eeprom_file: None,
+            saved_eeprom: [0; EEPROM_SIZE],
+            last: Instant::now(),
+            now: Instant::now(),
+            frame_duration: Duration::from_millis(100),
+        }
+    }
+
+    // Loads the eeprom image and remembers the file to save it back later
+    pub fn load_eeprom(&mut self, path: &Path) -> Result<(), String> {
+        self.cpu.load_eeprom(path)?;
+        self.eeprom_file = Some(path.to_path_buf());
+        self.saved_eeprom = self.cpu.eeprom().data;
+        Ok(())
+    }
+
+    // Writes the eeprom back to its file once a write of the program completed, like the
+    // content of a real eeprom survives power off. The bridge thread never returns, so this
+    // can not wait for the end of the program.
+    fn save_eeprom_changes(&mut self) {
+        let path = match &self.eeprom_file {
+            Some(path) if self.cpu.eeprom().data != self.saved_eeprom => path,
+            _ => return,
+        };
+
+        match self.cpu.save_eeprom(path) {
+            Ok(()) => self.saved_eeprom = self.cpu.eeprom().data,
+            Err(e) => println!("{}", e),
+        }
+    }
+
+    pub fn update(&mut self) {
+        self.now = Instant::now();
+
+        let work_time = self.now - self.last;
+
+        if work_time < self.frame_duration {
+            let delta = self.frame_duration - work_time;
+            std::thread::sleep(delta);
+        }
+
+        self.last = Instant::now();
+
+        if let Ok(data) = self.input.try_recv() {
+            for command in &data {
+                println!("{}", command);
+                self.handle_command(command);
+            }
+        }
+
+        // A breakpoint or watchpoint stops the program, the cpu reports the reason to the gui
+        if self.running && self.cpu.step().is_some() {
+            self.running = false;
+        }
+
+        self.save_eeprom_changes();
+    }
+
+    fn handle_command(&mut self, command: &str) {
+        if command.starts_with("C:\\") {
+            println!("Loading file: {}", command);
+            let content = fs::read_to_string(command).expect("Failed to open file");
+            match parse_program(command, &content) {
+                Ok(program) => {
+                    self.cpu.load(program);
+                    println!("Finished loading file");
+                }
+                Err(e) => println!("Failed to load {}: {}", command, e),
+            }
+            return;
+        }
+
+        let tokens: Vec<&str> = command.split(" ").collect();
+        match tokens[0] {
+            "STEP" => {
+                self.cpu.step();
+            }
+            "RESET" => {
+                // The reset button of the gui acts like the MCLR pin
+                let kind = if self.cpu.sleeping { ResetKind::MclrSleep } else { ResetKind::Mclr };
+                self.cpu.reset(kind);
+            },
+            "START" => self.running = true,
+            "STOPP" => self.running = false,
+            "XTAL" => {
+                let f_base = tokens[1].parse::<usize>().unwrap();
+                let f_mul = match tokens[2] {
+                    "kHz" => 1000,
+                    "MHz" => 1000000,
+                    _ => panic!("oopsie")
+                };
+
+                self.frame_duration = Duration::from_nanos(hertz::fps_to_ns_per_frame(f_base * f_mul));
+                self.cpu.cycle_duration = self.frame_duration * 4;
+            }
+            "EEPROM" => {
+                // The path may contain spaces
+                let path = command.splitn(3, ' ').nth(2).map(PathBuf::from);
+                let result = match (tokens.get(1).copied(), path) {
+                    (Some("LOAD"), Some(path)) => self.load_eeprom(&path),
+                    (Some("SAVE"), Some(path)) => self.cpu.save_eeprom(&path),
+                    (Some("SAVE"), None) => match &self.eeprom_file {
+                        Some(path) => self.cpu.save_eeprom(path),
+                        None => Err(String::from("No eeprom file loaded")),
+                    },
+                    _ => Err(format!("Unknown input command: {}", command)),
+                };
+
+                if let Err(e) = result {
+                    println!("{}", e);
+                }
+            }
+            "WATCHDOG" => match tokens[1] {
+                "ON" => self.cpu.rom_bus.set_watchdog_enabled(true),
+                "OFF" => self.cpu.rom_bus.set_watchdog_enabled(false),
+                _ => println!("Unknown input command: {}", command)
+            }
+            "STACKCHECK" => match tokens[1] {
+                "ON" => self.cpu.strict_stack = true,
+                "OFF" => self.cpu.strict_stack = false,
+                _ => println!("Unknown input command: {}", command)
+            }
+            "SETBREAK" | "RESBREAK" | "SETWATCH" | "RESWATCH" => {
+                if let Err(e) = self.handle_breakpoint(command) {
+                    println!("{}", e);
+                }
+            }
+            "PORTA" | "PORTB" => {
+                let tmp: Vec<&str> = tokens[1].split(",").collect();
+                let idx = tmp[0].parse::<usize>().unwrap();
+                let bit = tmp[1].parse::<u8>().unwrap();
+
+                if tokens[0] == "PORTA" {
+                    self.cpu.set_porta_pin(idx, bit != 0);
+                } else {
+                    self.cpu.set_portb_pin(idx, bit != 0);
+                }
+            }
+            "TRISA" | "TRISB" => {
+                let tmp: Vec<&str> = tokens[1].split(",").collect();
+                let idx = tmp[0].parse::<usize>().unwrap();
+                let bit = tmp[1].parse::<u8>().unwrap();
+
+                // The tris registers are located in bank 1
+                let address = if tokens[0] == "TRISA" { TRISA_ADDR } else { TRISB_ADDR } | BANK1_ADDR;
+                let mut value = self.cpu.register(address);
+                set_bit_enabled(&mut value, idx, bit != 0);
+                self.cpu.write_register(address, value);
+            }
+            _ => println!("Unknown input command: {}", command)
+        };
+    }
+
+    // SETBREAK <line> [HITS <n>] [IF <condition>], RESBREAK <line>,
+    // SETWATCH <register> <R|W|RW> and RESWATCH <register>
+    fn handle_breakpoint(&mut self, command: &str) -> Result<(), String> {
+        let mut tokens = command.split_whitespace();
+        let kind = tokens.next().unwrap_or("");
+        let target = tokens.next().ok_or_else(|| format!("Missing argument: {}", command))?;
+        let info = self.cpu.program_info();
+
+        match kind {
+            "SETBREAK" | "RESBREAK" => {
+                let line = target.parse::<usize>().map_err(|_| format!("Invalid line: {}", command))?;
+                let address = info.address_of(line).ok_or_else(|| format!("Line {} has no instruction", line))?;
+
+                if kind == "RESBREAK" {
+                    self.cpu.breakpoints.remove(address);
+                    return Ok(());
+                }
+
+                let rest: Vec<&str> = tokens.collect();
+                let (hits, condition) = match rest.as_slice() {
+                    ["HITS", hits, rest @ ..] => {
+                        let hits = hits.parse::<usize>().map_err(|_| format!("Invalid hit count: {}", command))?;
+                        (hits, rest)
+                    }
+                    rest => (1, rest),
+                };
+                let condition = match condition {
+                    [] => None,
+                    ["IF", condition @ ..] => Some(Condition::parse(&condition.join(" "), info)?),
+                    _ => return Err(format!("Unknown input command: {}", command)),
+                };
+
+                // The gui counts the hit that stops, the core the hits that pass
+                self.cpu.breakpoints.set(address, condition, hits.saturating_sub(1));
+            }
+            "SETWATCH" => {
+                let address = register_address(target, info)?;
+                let access = match tokens.next() {
+                    Some("R") => Access::Read,
+                    Some("W") | None => Access::Write,
+                    Some("RW") => Access::ReadWrite,
+                    _ => return Err(format!("Unknown input command: {}", command)),
+                };
+                self.cpu.data_bus.watchpoints.set(address, access);
+            }
+            _ => {
+                let address = register_address(target, info)?;
+                self.cpu.data_bus.watchpoints.remove(address);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Sends the protocol lines of every event to the file writer
+struct ProtocolWriter {
+    output: Sender<Vec<String>>,
+}
+
+impl Observer for ProtocolWriter {
+    fn on_event(&mut self, event: &SimEvent) {
+        let lines = to_protocol(event);
+        if !lines.is_empty() {
+            let _ = self.output.send(lines);
+        }
+    }
+}
@@ -1,18 +1,15 @@
-#[macro_use]
-extern crate log;
 extern crate hertz;
 extern crate notify;
 
-use crate::emulator::{CPU};
+use crate::gui::GuiBridge;
 use std::sync::mpsc::channel;
-use std::path::Path;
-use std::fs::File;
-use std::io::{BufReader, BufRead, Write};
+use std::path::{Path, PathBuf};
 use std::fs;
-use regex::internal::Input;
 use std::collections::VecDeque;
 
-mod emulator;
+mod cli;
+mod debugger;
+mod gui;
 
 const INPUT: &str = "gui_change.dat";
 const OUTPUT: &str = "gui_set.dat";
@@ -26,18 +23,43 @@ fn output_available() -> bool {
 }
 
 fn main() {
+    // Headless modes for scripts and the terminal, everything else talks to GUI_PicSim
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("run") => std::process::exit(cli::run(&args[2..])),
+        Some("debug") => std::process::exit(debugger::run(&args[2..])),
+        _ => {}
+    }
+
     // TODO: report all data at specific rate when running and when stopped
     simple_logger::init().unwrap();
 
-    fs::remove_file(INPUT);
-    fs::remove_file(OUTPUT);
+    let _ = fs::remove_file(INPUT);
+    let _ = fs::remove_file(OUTPUT);
 
     let (input_tx, input_rx) = channel();
     let (output_tx, output_rx) = channel();
 
-    std::thread::spawn(|| {
-        let mut cpu = CPU::new(input_rx, output_tx);
-        loop { cpu.update(); }
+    // Optional eeprom image which is loaded on start and saved back after every write
+    let eeprom_file = std::env::args()
+        .skip_while(|arg| arg != "--eeprom")
+        .nth(1)
+        .map(PathBuf::from);
+    let strict_stack = std::env::args().any(|arg| arg == "--strict-stack");
+
+    std::thread::spawn(move || {
+        let mut gui = GuiBridge::new(input_rx, output_tx);
+        gui.cpu.strict_stack = strict_stack;
+
+        if let Some(path) = eeprom_file {
+            if !path.exists() {
+                gui.eeprom_file = Some(path);
+            } else if let Err(e) = gui.load_eeprom(&path) {
+                println!("{}", e);
+            }
+        }
+
+        loop { gui.update(); }
     });
 
     let mut commands = VecDeque::new();
@@ -54,32 +76,27 @@ fn main() {
                         input.push(String::from(line));
                     }
 
-                    input_tx.send(input);
+                    let _ = input_tx.send(input);
                     break;
                 }
             }
         }
 
-        match output_rx.try_recv() {
-            Ok(data) => {
-                commands.extend(data)
-            },
-            _ => {}
+        for data in output_rx.try_iter() {
+            commands.extend(data)
         }
 
-        if output_available() {
-            if commands.len() > 0 {
-                let mut result = saved_string.clone();
-                saved_string.clear();
+        if output_available() && !commands.is_empty() {
+            let mut result = saved_string.clone();
+            saved_string.clear();
 
-                for i in 0..std::cmp::min(commands.len(), 1000) {
-                    result += "\n";
-                    result += &commands.pop_front().unwrap();
-                }
+            for _ in 0..std::cmp::min(commands.len(), 1000) {
+                result += "\n";
+                result += &commands.pop_front().unwrap();
+            }
 
-               if let Err(_) = fs::write(OUTPUT, &result) {
-                   saved_string = result;
-               }
+            if fs::write(OUTPUT, &result).is_err() {
+                saved_string = result;
             }
         }
     }